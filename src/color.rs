@@ -0,0 +1,210 @@
+// Colors for diff and log output.
+//
+// `--color` picks the policy once, `auto` then defers to `color.diff` and
+// `color.ui` the way git does, and `NO_COLOR` turns auto coloring off.
+// The palette is read from `color.diff.<slot>` with git's defaults.
+
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use git2::Repository;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ColorMode {
+    Auto = 1,
+    Always,
+    Never,
+}
+
+static MODE: AtomicUsize = AtomicUsize::new(ColorMode::Auto as usize);
+
+impl ColorMode {
+    pub fn from_name(name: &str) -> Option<ColorMode> {
+        match name {
+            // like git, a color setting of true only colors for a terminal
+            "auto" | "true" | "yes" | "on" | "1" => Some(ColorMode::Auto),
+            "always" => Some(ColorMode::Always),
+            "never" | "false" | "no" | "off" | "0" => Some(ColorMode::Never),
+            _ => None,
+        }
+    }
+}
+
+pub fn set_mode(mode: ColorMode) {
+    MODE.store(mode as usize, Ordering::Relaxed);
+}
+
+fn mode() -> ColorMode {
+    match MODE.load(Ordering::Relaxed) {
+        2 => ColorMode::Always,
+        3 => ColorMode::Never,
+        _ => ColorMode::Auto,
+    }
+}
+
+// Whether to color output of the given kind ("diff" for diff and log),
+// which has to be asked before a pager takes over stdout.
+pub fn enabled(repo: &Repository, kind: &str) -> bool {
+    let mut mode = mode();
+    if mode == ColorMode::Auto {
+        if let Ok(config) = repo.config() {
+            let configured = config.get_string(&format!("color.{}", kind))
+                .or_else(|_| config.get_string("color.ui"))
+                .ok()
+                .and_then(|value| ColorMode::from_name(&value.to_lowercase()));
+            mode = configured.unwrap_or(ColorMode::Auto);
+        }
+    }
+    match mode {
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+        ColorMode::Auto => {
+            env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                && atty::is(atty::Stream::Stdout)
+        }
+    }
+}
+
+pub struct Palette {
+    pub meta: String,
+    pub frag: String,
+    pub old: String,
+    pub new: String,
+    pub commit: String,
+    pub reset: String,
+}
+
+impl Palette {
+    // Everything empty, for output which is not colored.
+    pub fn plain() -> Palette {
+        Palette {
+            meta: String::new(),
+            frag: String::new(),
+            old: String::new(),
+            new: String::new(),
+            commit: String::new(),
+            reset: String::new(),
+        }
+    }
+}
+
+pub fn palette(repo: &Repository, kind: &str) -> Palette {
+    if !enabled(repo, kind) {
+        return Palette::plain();
+    }
+    let config = repo.config().ok();
+    let slot = |name: &str, default: &str| {
+        let value = config.as_ref()
+            .and_then(|config| config.get_string(&format!("color.diff.{}", name)).ok());
+        value.as_deref().and_then(parse).unwrap_or_else(|| parse(default).unwrap_or_default())
+    };
+    Palette {
+        meta: slot("meta", "bold"),
+        frag: slot("frag", "cyan"),
+        old: slot("old", "red"),
+        new: slot("new", "green"),
+        commit: slot("commit", "yellow"),
+        reset: "\u{1b}[m".to_owned(),
+    }
+}
+
+// A git color value such as "bold red", "brightblue black ul" or
+// "#ff8800", as an escape sequence. The first color is the foreground
+// and the second one the background.
+pub fn parse(value: &str) -> Option<String> {
+    let mut codes = Vec::new();
+    let mut colors = 0;
+    for word in value.split_whitespace() {
+        let word = word.to_lowercase();
+        let attr = match word.trim_start_matches("no").trim_start_matches('-') {
+            "bold" => Some(1),
+            "dim" => Some(2),
+            "italic" => Some(3),
+            "ul" => Some(4),
+            "blink" => Some(5),
+            "reverse" => Some(7),
+            "strike" => Some(9),
+            _ => None,
+        };
+        if let Some(attr) = attr {
+            if word.starts_with("no") {
+                codes.push(match attr { 1 | 2 => 22, n => 20 + n }.to_string());
+            } else {
+                codes.push(attr.to_string());
+            }
+            continue;
+        }
+
+        let base = if colors == 0 { 30 } else { 40 };
+        let code = if word == "normal" {
+            None
+        } else if word == "default" {
+            Some((base + 9).to_string())
+        } else if let Some(n) = color_index(word.trim_start_matches("bright")) {
+            if word.starts_with("bright") {
+                Some((base + 60 + n).to_string())
+            } else {
+                Some((base + n).to_string())
+            }
+        } else if let Ok(n) = word.parse::<u8>() {
+            Some(format!("{};5;{}", base + 8, n))
+        } else if let Some(hex) = word.strip_prefix('#').filter(|hex| hex.len() == 6) {
+            let rgb = u32::from_str_radix(hex, 16).ok()?;
+            Some(format!("{};2;{};{};{}", base + 8, rgb >> 16, rgb >> 8 & 0xff, rgb & 0xff))
+        } else {
+            return None;
+        };
+        if colors == 2 {
+            return None;
+        }
+        colors += 1;
+        codes.extend(code);
+    }
+    if codes.is_empty() {
+        Some(String::new())
+    } else {
+        Some(format!("\u{1b}[{}m", codes.join(";")))
+    }
+}
+
+fn color_index(name: &str) -> Option<u8> {
+    ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"]
+        .iter()
+        .position(|color| *color == name)
+        .map(|n| n as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn true_means_auto() {
+        assert!(ColorMode::from_name("true") == Some(ColorMode::Auto));
+        assert!(ColorMode::from_name("auto") == Some(ColorMode::Auto));
+        assert!(ColorMode::from_name("always") == Some(ColorMode::Always));
+        assert!(ColorMode::from_name("false") == Some(ColorMode::Never));
+        assert!(ColorMode::from_name("never") == Some(ColorMode::Never));
+        assert!(ColorMode::from_name("sometimes").is_none());
+    }
+
+    #[test]
+    fn parse_attributes_and_colors() {
+        assert_eq!(parse("bold").as_deref(), Some("\u{1b}[1m"));
+        assert_eq!(parse("bold red").as_deref(), Some("\u{1b}[1;31m"));
+        assert_eq!(parse("red black").as_deref(), Some("\u{1b}[31;40m"));
+        assert_eq!(parse("brightblue nobold").as_deref(), Some("\u{1b}[94;22m"));
+        assert_eq!(parse("no-ul").as_deref(), Some("\u{1b}[24m"));
+        assert_eq!(parse("normal default").as_deref(), Some("\u{1b}[49m"));
+        assert_eq!(parse("208").as_deref(), Some("\u{1b}[38;5;208m"));
+        assert_eq!(parse("#ff8800").as_deref(), Some("\u{1b}[38;2;255;136;0m"));
+        assert_eq!(parse("").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn parse_rejects_bad_values() {
+        assert_eq!(parse("purple"), None);
+        assert_eq!(parse("red green blue"), None);
+        assert_eq!(parse("#ff88"), None);
+    }
+}
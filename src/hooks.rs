@@ -0,0 +1,173 @@
+// Flow hooks, named and called like the ones of git-flow AVH.
+//
+// `pre-flow-<kind>-<command>` runs before an operation and stops it by
+// exiting non-zero, `post-flow-<kind>-<command>` runs after it succeeded.
+// Both get the name, the remote and the full branch name as arguments and
+// run from the top of the work tree. Filter hooks such as
+// `filter-flow-release-start-version` rewrite a value on its way instead.
+// Hooks live in `gitflow.path.hooks`, `core.hooksPath` or the hooks
+// directory of the repository.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use git2::{Error, Repository};
+
+// The directory relative paths in the configuration are taken from.
+fn top_dir(repo: &Repository) -> &Path {
+    repo.workdir().unwrap_or_else(|| repo.path())
+}
+
+pub fn hooks_dir(repo: &Repository) -> PathBuf {
+    let config = repo.config().ok();
+    let configured = config.as_ref().and_then(|config| {
+        config.get_path("gitflow.path.hooks")
+            .or_else(|_| config.get_path("core.hooksPath"))
+            .ok()
+    });
+    match configured {
+        Some(dir) => top_dir(repo).join(dir),
+        None => repo.path().join("hooks"),
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata().map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+// The hook's path, when there is one which can be run.
+pub fn find(repo: &Repository, name: &str) -> Option<PathBuf> {
+    let path = hooks_dir(repo).join(name);
+    if is_executable(&path) {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+// Missing hooks count as succeeded.
+pub fn run(repo: &Repository, name: &str, args: &[&str]) -> Result<(), Error> {
+    let path = match find(repo, name) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let status = Command::new(&path)
+        .args(args)
+        .current_dir(top_dir(repo))
+        .status()
+        .map_err(|e| Error::from_str(&format!("Can't run the {} hook: {}", name, e)))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::from_str(&match status.code() {
+            Some(code) => format!("The {} hook exited with {}", name, code),
+            None => format!("The {} hook was killed", name),
+        }))
+    }
+}
+
+// Filter hooks get the value as the first argument and on stdin, followed
+// by `args`, and print the value to use instead. Without a hook the value
+// is kept.
+pub fn filter(repo: &Repository, name: &str, value: &str, args: &[&str]) -> Result<String, Error> {
+    let path = match find(repo, name) {
+        Some(path) => path,
+        None => return Ok(value.to_owned()),
+    };
+    let mut child = Command::new(&path)
+        .arg(value)
+        .args(args)
+        .current_dir(top_dir(repo))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::from_str(&format!("Can't run the {} hook: {}", name, e)))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // a filter which doesn't read its stdin is fine
+        stdin.write_all(value.as_bytes()).unwrap_or(());
+    }
+    let output = child.wait_with_output()
+        .map_err(|e| Error::from_str(&format!("Can't run the {} hook: {}", name, e)))?;
+    if !output.status.success() {
+        return Err(Error::from_str(&format!("The {} hook failed", name)));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim_end().to_owned())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::tests::temp_repo;
+
+    fn write_hook(dir: &Path, name: &str, script: &str) {
+        fs::create_dir_all(dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn run_passes_arguments_from_the_top_dir() {
+        let tmp = temp_repo("hooks-run");
+        let repo = &tmp.repo;
+        write_hook(&hooks_dir(repo), "post-flow-feature-start", "echo \"$@\" > args");
+
+        run(repo, "post-flow-feature-start", &["login", "origin", "feature/login"]).unwrap();
+        let args = fs::read_to_string(repo.workdir().unwrap().join("args")).unwrap();
+        assert_eq!(args, "login origin feature/login\n");
+    }
+
+    #[test]
+    fn run_fails_with_the_hook() {
+        let tmp = temp_repo("hooks-fail");
+        let repo = &tmp.repo;
+        write_hook(&hooks_dir(repo), "pre-flow-feature-start", "exit 3");
+
+        let err = run(repo, "pre-flow-feature-start", &["login"]).unwrap_err();
+        assert_eq!(err.message(), "The pre-flow-feature-start hook exited with 3");
+        assert!(run(repo, "pre-flow-feature-finish", &["login"]).is_ok());
+    }
+
+    #[test]
+    fn hooks_are_found_where_configured() {
+        let tmp = temp_repo("hooks-dir");
+        let repo = &tmp.repo;
+        assert_eq!(hooks_dir(repo), repo.path().join("hooks"));
+
+        repo.config().unwrap().set_str("core.hooksPath", "githooks").unwrap();
+        assert_eq!(hooks_dir(repo), repo.workdir().unwrap().join("githooks"));
+        repo.config().unwrap().set_str("gitflow.path.hooks", "flowhooks").unwrap();
+        let dir = repo.workdir().unwrap().join("flowhooks");
+        assert_eq!(hooks_dir(repo), dir);
+
+        write_hook(&dir, "pre-flow-release-start", "exit 0");
+        fs::write(dir.join("post-flow-release-start"), "#!/bin/sh\n").unwrap();
+        assert_eq!(find(repo, "pre-flow-release-start"), Some(dir.join("pre-flow-release-start")));
+        assert_eq!(find(repo, "post-flow-release-start"), None);
+    }
+
+    #[test]
+    fn filter_rewrites_the_value() {
+        let tmp = temp_repo("hooks-filter");
+        let repo = &tmp.repo;
+        assert_eq!(filter(repo, "filter-flow-release-start-version", "1.0", &[]).unwrap(), "1.0");
+
+        write_hook(&hooks_dir(repo), "filter-flow-release-start-version", "read value; echo \"$value.$3\"");
+        assert_eq!(filter(repo, "filter-flow-release-start-version", "1.0", &["x", "3"]).unwrap(), "1.0.3");
+
+        write_hook(&hooks_dir(repo), "filter-flow-hotfix-start-version", "exit 1");
+        assert!(filter(repo, "filter-flow-hotfix-start-version", "1.0.1", &[]).is_err());
+    }
+}
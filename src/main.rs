@@ -1,25 +1,34 @@
 extern crate clap;
 extern crate rpassword;
 
+#[macro_use]
+mod output;
+mod color;
+mod hooks;
+mod pager;
+mod progress;
+mod rebase;
+mod worktree;
+
 use std::str;
 use std::string::String;
-use std::path::Path;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::fs::{self, File};
-use std::io::{self, stdin, stdout, Read, Write};
+use std::fs;
+use std::io::{self, Write};
 use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
 use git2::*;
-use clap::{Arg, App, SubCommand};
+use clap::{Arg, App, ArgMatches, SubCommand};
+use serde_json::json;
 
-const RESET: &str = "\u{1b}[m";
-const BOLD: &str = "\u{1b}[1m";
-const RED: &str = "\u{1b}[31m";
-const GREEN: &str = "\u{1b}[32m";
-const CYAN: &str = "\u{1b}[36m";
+const FLOW_KINDS: [&str; 5] = ["feature", "bugfix", "release", "hotfix", "support"];
 
 // TODO 3. Reconstruct the codes
 
-fn create_initial_commit(repo: &Repository) -> Result<(), Error> {
+fn create_initial_commit(repo: &Repository, refname: &str) -> Result<(), Error> {
     // First use the config to initialize a commit signature for the user.
     let sig = repo.signature()?;
 
@@ -41,54 +50,56 @@ fn create_initial_commit(repo: &Repository) -> Result<(), Error> {
     // Normally creating a commit would involve looking up the current HEAD
     // commit and making that be the parent of the initial commit, but here this
     // is the first commit so there will be no parent.
-    repo.commit(Some("HEAD"), &sig, &sig, "Initial commit", &tree, &[])?;
+    repo.commit(Some(refname), &sig, &sig, "Initial commit", &tree, &[])?;
 
     Ok(())
 }
 
+// A branch checked out in another worktree is left alone.
+fn check_not_elsewhere(repo: &Repository, br_name: &str) -> Result<(), Error> {
+    match worktree::elsewhere(repo, br_name)? {
+        Some(path) => Err(Error::from_str(&format!("{} is checked out in the worktree {}", br_name, path.display()))),
+        None => Ok(()),
+    }
+}
+
 fn checkout_branch(repo: &Repository, br_name: &str) -> Result<(), Error> {
+    check_not_elsewhere(repo, br_name)?;
     let refs_tree = &("refs/heads/".to_owned() + br_name);
-    let obj = repo.revparse_single(&refs_tree)?;
+    let obj = repo.revparse_single(refs_tree)?;
     repo.checkout_tree(&obj, None)?;
-    repo.set_head(&refs_tree)?;
+    repo.set_head(refs_tree)?;
 
     Ok(())
 }
 
-fn tree_to_treeish<'a>(repo: &'a Repository, br_name: &str)
-    -> Result<Option<Object<'a>>, Error> {
-    let obj = repo.revparse_single(&br_name)?;
-    let tree = obj.peel(ObjectType::Tree)?;
-        Ok(Some(tree))
-}
-
 fn create_checkout_branch(repo: &Repository, br_name: &str, base_br: Option<&str>, oid_str: Option<&str>) -> Result<(), Error> {
-    let oid: Oid;
-    if oid_str == None {
-        if base_br != None {
-            let base_br_ref = &("refs/heads/".to_owned() + base_br.unwrap());
+    let oid = if let Some(oid_str) = oid_str {
+        Oid::from_str(oid_str)?
+    } else {
+        if let Some(base_br) = base_br {
+            let base_br_ref = &("refs/heads/".to_owned() + base_br);
             // set head to the base branch
             repo.set_head(base_br_ref)?;
         }
         let head = repo.head()?;
-        oid = head.target().unwrap();
-    } else {
-        oid = Oid::from_str(oid_str.unwrap())?;
-    }
+        head.target().unwrap()
+    };
     let commit = repo.find_commit(oid)?;
     repo.branch(br_name, &commit, false)?;
 
-    checkout_branch(&repo, br_name)?;
+    checkout_branch(repo, br_name)?;
 
     Ok(())
 }
 
-fn find_last_commit(repo: &Repository) -> Result<Commit, Error> {
+fn find_last_commit(repo: &Repository) -> Result<Commit<'_>, Error> {
     let obj = repo.head()?.resolve()?.peel(ObjectType::Commit)?;
     obj.into_commit().map_err(|_| Error::from_str("Couldn't find commit"))
 }
 
 fn fastforward_merge_branch(repo: &Repository, our_br: &str, their_br: &str) -> Result<(), Error> {
+    check_not_elsewhere(repo, our_br)?;
     let their_oid = repo.refname_to_id(&("refs/heads/".to_owned() + their_br))?;
     let our_refname = &("refs/heads/".to_owned() + our_br);
     let mut our_ref = repo.find_reference(our_refname)?;
@@ -103,26 +114,18 @@ fn do_fetch<'a>(
     refs: &[&str],
     remote: &'a mut git2::Remote,
 ) -> Result<git2::AnnotatedCommit<'a>, git2::Error> {
+    let progress = progress::reporter("fetch", remote.name().unwrap_or(""));
     let mut cb = git2::RemoteCallbacks::new();
 
-    // Print out our transfer progress.
+    // Report our transfer progress.
     cb.transfer_progress(|stats| {
         if stats.received_objects() == stats.total_objects() {
-            print!(
-                "Resolving deltas {}/{}\r",
-                stats.indexed_deltas(),
-                stats.total_deltas()
-            );
+            progress.update("resolving", stats.indexed_deltas(), stats.total_deltas(),
+                stats.received_bytes());
         } else if stats.total_objects() > 0 {
-            print!(
-                "Received {}/{} objects ({}) in {} bytes\r",
-                stats.received_objects(),
-                stats.total_objects(),
-                stats.indexed_objects(),
-                stats.received_bytes()
-            );
+            progress.update("receiving", stats.received_objects(), stats.total_objects(),
+                stats.received_bytes());
         }
-        io::stdout().flush().unwrap();
         true
     });
 
@@ -131,32 +134,31 @@ fn do_fetch<'a>(
     // Always fetch all tags.
     // Perform a download and also update tips
     fo.download_tags(git2::AutotagOption::All);
-    println!("Fetching {} for repo", remote.name().unwrap());
+    say!("Fetching {} for repo", remote.name().unwrap());
     remote.fetch(refs, Some(&mut fo), None)?;
 
     // If there are local objects (we got a thin pack), then tell the user
     // how many objects we saved from having to cross the network.
     let stats = remote.stats();
     if stats.local_objects() > 0 {
-        println!(
-            "\rReceived {}/{} objects in {} bytes (used {} local \
-             objects)",
+        progress.done(&format!(
+            "Received {}/{} objects in {} (used {} local objects)",
             stats.indexed_objects(),
             stats.total_objects(),
-            stats.received_bytes(),
+            progress::human_bytes(stats.received_bytes()),
             stats.local_objects()
-        );
+        ));
     } else {
-        println!(
-            "\rReceived {}/{} objects in {} bytes",
+        progress.done(&format!(
+            "Received {}/{} objects in {}",
             stats.indexed_objects(),
             stats.total_objects(),
-            stats.received_bytes()
-        );
+            progress::human_bytes(stats.received_bytes())
+        ));
     }
 
     let fetch_head = repo.find_reference("FETCH_HEAD")?;
-    Ok(repo.reference_to_annotated_commit(&fetch_head)?)
+    repo.reference_to_annotated_commit(&fetch_head)
 }
 
 fn normal_merge(
@@ -172,7 +174,7 @@ fn normal_merge(
     let mut idx = repo.merge_trees(&ancestor, &local_tree, &remote_tree, None)?;
 
     if idx.has_conflicts() {
-        println!("Merge conficts detected...");
+        say!("Merge conficts detected...");
         repo.checkout_index(Some(&mut idx), None)?;
         return Ok(());
     }
@@ -206,7 +208,7 @@ fn fast_forward(
         None => String::from_utf8_lossy(lb.name_bytes()).to_string(),
     };
     let msg = format!("Fast-Forward: Setting {} to id: {}", name, rc.id());
-    println!("{}", msg);
+    say!("{}", msg);
     lb.set_target(rc.id(), &msg)?;
     repo.set_head(&name)?;
     repo.checkout_head(Some(
@@ -229,7 +231,7 @@ fn do_merge<'a>(
 
     // 2. Do the appopriate merge
     if analysis.0.is_fast_forward() {
-        println!("Doing a fast forward");
+        say!("Doing a fast forward");
         // do a fast forward
         let refname = format!("refs/heads/{}", remote_branch);
         match repo.find_reference(&refname) {
@@ -258,21 +260,80 @@ fn do_merge<'a>(
     } else if analysis.0.is_normal() {
         // do a normal merge
         let head_commit = repo.reference_to_annotated_commit(&repo.head()?)?;
-        normal_merge(&repo, &head_commit, &fetch_commit)?;
+        normal_merge(repo, &head_commit, &fetch_commit)?;
     } else {
-        println!("Nothing to do...");
+        say!("Nothing to do...");
     }
     Ok(())
 }
 
-fn normal_merge_branch(repo: &Repository, our_br: &str, their_br: &str) -> Result<(), Error> {
+// The flow branch a merge belongs to, for merge messages. While finishing
+// a release the merge back from master still belongs to the release.
+struct MergeContext<'a> {
+    kind: &'a str,
+    name: &'a str,
+    branch: &'a str,
+    tag: Option<&'a str>,
+}
+
+// Templates are looked up per kind and target (master, develop or base
+// for any other branch), as gitflow.<kind>.message.<target>, then for all
+// kinds as gitflow.message.<target>.
+fn merge_message(repo: &Repository, ctx: &MergeContext, our_br: &str, their_br: &str) -> Result<String, Error> {
+    let target = if our_br == gf_master(repo) {
+        "master"
+    } else if our_br == gf_develop(repo) {
+        "develop"
+    } else {
+        "base"
+    };
+    let template = gf_config_or(repo, &format!("gitflow.{}.message.{}", ctx.kind, target),
+        &gf_config_or(repo, &format!("gitflow.message.{}", target), "Merge {branch} into {base}\n\n{commits}"));
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    revwalk.push_ref(&("refs/heads/".to_owned() + their_br))?;
+    revwalk.hide_ref(&("refs/heads/".to_owned() + our_br))?;
+    let mut commits = Vec::new();
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        if commit.parent_count() < 2 {
+            commits.push(format!("* {}", commit.summary().unwrap_or("")));
+        }
+    }
+
+    let version = if ctx.kind == "release" || ctx.kind == "hotfix" { ctx.name } else { "" };
+    let msg = template
+        .replace("{kind}", ctx.kind)
+        .replace("{name}", ctx.name)
+        .replace("{branch}", ctx.branch)
+        .replace("{base}", our_br)
+        .replace("{version}", version)
+        .replace("{tag}", ctx.tag.unwrap_or(""))
+        .replace("{commits}", &commits.join("\n"));
+    // placeholders which came out empty shouldn't leave gaps behind
+    let msg = msg.lines().map(|line| line.trim_end()).collect::<Vec<_>>().join("\n");
+    let mut msg = msg.trim().to_owned();
+    while msg.contains("\n\n\n") {
+        msg = msg.replace("\n\n\n", "\n\n");
+    }
+
+    Ok(msg)
+}
+
+fn normal_merge_branch(repo: &Repository, ctx: &MergeContext, our_br: &str, their_br: &str) -> Result<(), Error> {
     let their_oid = repo.refname_to_id(&("refs/heads/".to_owned() + their_br))?;
     let their_commit = repo.find_commit(their_oid)?;
     let their_annotated_commit = repo.find_annotated_commit(their_oid)?;
+    let merge_msg = merge_message(repo, ctx, our_br, their_br)?;
+    let merge_msg = hooks::filter(repo, &format!("filter-flow-{}-finish-merge-message", ctx.kind),
+                                  &merge_msg, &[our_br, their_br])?;
+    let msg = edit_msg(repo, "MERGE_MSG", &merge_msg,
+                       &format!("Please enter the message for merging {} into {}.", their_br, our_br))?;
 
-    checkout_branch(&repo, our_br)?;
+    checkout_branch(repo, our_br)?;
     repo.merge(&[&their_annotated_commit], None, None)?;
-    let parent = find_last_commit(&repo)?;
+    let parent = find_last_commit(repo)?;
 
     //git commit
     let sig = repo.signature()?;
@@ -284,16 +345,6 @@ fn normal_merge_branch(repo: &Repository, our_br: &str, their_br: &str) -> Resul
 
     let tree = repo.find_tree(tree_id)?;
 
-    let merge_msg: String;
-    if our_br == "master" {
-        merge_msg = "Bump to version ".to_owned();
-    } else if our_br == "develop" {
-        merge_msg = "Develop from version ".to_owned();
-    } else {
-        merge_msg = "Merge ".to_owned() + their_br + " to " + our_br;
-    }
-    let msg = edit_msg(".git/COMMIT_EDITMSG", &merge_msg);
-
     repo.commit(Some("HEAD"), &sig, &sig, &msg, &tree, &[&parent, &their_commit])?;
 
     // reslove conflicts and merging
@@ -302,317 +353,2526 @@ fn normal_merge_branch(repo: &Repository, our_br: &str, their_br: &str) -> Resul
     Ok(())
 }
 
-fn merge_branch(repo: &Repository, our_br: &str, their_br: &str, ff: bool) -> Result<(), Error> {
+// All of their_br's changes as a single commit on our_br.
+fn squash_merge_branch(repo: &Repository, ctx: &MergeContext, our_br: &str, their_br: &str) -> Result<(), Error> {
+    let our_commit = repo.find_reference(&("refs/heads/".to_owned() + our_br))?.peel_to_commit()?;
+    let their_commit = repo.find_reference(&("refs/heads/".to_owned() + their_br))?.peel_to_commit()?;
+
+    let mut index = repo.merge_commits(&our_commit, &their_commit, None)?;
+    if index.has_conflicts() {
+        return Err(Error::from_str(&format!("{} doesn't squash cleanly onto {}, rebase it first", their_br, our_br)));
+    }
+    let tree = repo.find_tree(index.write_tree_to(repo)?)?;
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    revwalk.push(their_commit.id())?;
+    revwalk.hide(our_commit.id())?;
+    let mut squashed = Vec::new();
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        if commit.parent_count() < 2 {
+            squashed.push(format!("* {} ({})", commit.summary().unwrap_or(""), commit.author().name().unwrap_or("")));
+        }
+    }
+    if squashed.is_empty() {
+        return Err(Error::from_str(&format!("{} has nothing to squash into {}", their_br, our_br)));
+    }
+
+    let msg = format!("Squash {} into {}\n\n{}", ctx.branch, our_br, squashed.join("\n"));
+    let msg = hooks::filter(repo, &format!("filter-flow-{}-finish-merge-message", ctx.kind),
+                            &msg, &[our_br, their_br])?;
+    let msg = edit_msg(repo, "SQUASH_MSG", &msg,
+                       &format!("Please enter the message for squashing {} into {}.", their_br, our_br))?;
+
+    checkout_branch(repo, our_br)?;
+    repo.checkout_tree(tree.as_object(), None)?;
+    let sig = repo.signature()?;
+    repo.commit(Some("HEAD"), &sig, &sig, &msg, &tree, &[&our_commit])?;
+
+    Ok(())
+}
+
+fn merge_branch(repo: &Repository, ctx: &MergeContext, our_br: &str, their_br: &str, ff: bool) -> Result<(), Error> {
 
     if ff {
-        fastforward_merge_branch(&repo, our_br, their_br)?;
+        fastforward_merge_branch(repo, our_br, their_br)?;
     } else {
-        normal_merge_branch(&repo, our_br, their_br)?;
+        normal_merge_branch(repo, ctx, our_br, their_br)?;
     }
 
     // checkout to base branch
-    checkout_branch(&repo, our_br)?;
+    checkout_branch(repo, our_br)?;
 
     Ok(())
 }
 
 fn delete_branch(repo: &Repository, br_name: &str) -> Result<(), Error> {
-    let mut branch = repo.find_branch(&br_name, BranchType::Local)?;
+    check_not_elsewhere(repo, br_name)?;
+    let mut branch = repo.find_branch(br_name, BranchType::Local)?;
     branch.delete()?;
+    // the recorded base and pushes still waiting go with it
+    if let Ok(mut config) = repo.config().and_then(|config| config.open_level(ConfigLevel::Local)) {
+        config.remove(&format!("gitflow.branch.{}.base", br_name)).unwrap_or(());
+    }
+    forget_pending_push(repo, br_name);
 
     Ok(())
 }
 
-fn gf_init<P: AsRef<Path>>(path: P) -> Result<(), Error> {
-    let repo = Repository::init(path)?;
-    let mut config_l = repo.config()?;
+// Carry the recorded bases over to the new name of a renamed branch: its
+// own, and those of the branches started from it.
+fn rename_base(repo: &Repository, old_name: &str, new_name: &str) -> Result<(), Error> {
+    let mut config = repo.config()?.open_level(ConfigLevel::Local)?;
+    let key = |br_name: &str| format!("gitflow.branch.{}.base", br_name);
+    if let Ok(base) = config.get_string(&key(old_name)) {
+        config.set_str(&key(new_name), &base)?;
+        config.remove(&key(old_name))?;
+    }
 
-    // create an initial commit for master branch
-    create_initial_commit(&repo)?;
-    config_l.set_str("gitflow.branch.master", "master")?;
+    let mut stacked = Vec::new();
+    for entry in &config.entries(Some("^gitflow\\.branch\\..*\\.base$"))? {
+        let entry = entry?;
+        if entry.value() == Some(old_name) {
+            stacked.extend(entry.name().map(str::to_owned));
+        }
+    }
+    for name in stacked {
+        config.set_str(&name, new_name)?;
+    }
+    Ok(())
+}
 
-    // git checkout -b develop master
-    create_checkout_branch(&repo, "develop", Some("master"), None)?;
-    config_l.set_str("gitflow.branch.develop", "develop")?;
+fn gf_config_or(repo: &Repository, key: &str, default: &str) -> String {
+    repo.config()
+        .and_then(|cfg| cfg.get_string(key))
+        .unwrap_or_else(|_| default.to_owned())
+}
 
-    config_l.set_str("gitflow.prefix.feature", "feature/")?;
-    config_l.set_str("gitflow.prefix.release", "release/")?;
-    config_l.set_str("gitflow.prefix.hotfix", "hotfix/")?;
-    config_l.set_str("gitflow.prefix.bugfix", "bugfix/")?;
-    config_l.set_str("gitflow.prefix.support", "support/")?;
-    config_l.set_str("gitflow.prefix.versiontag", "")?;
+fn gf_master(repo: &Repository) -> String {
+    gf_config_or(repo, "gitflow.branch.master", "master")
+}
 
-    Ok(())
+fn gf_develop(repo: &Repository) -> String {
+    gf_config_or(repo, "gitflow.branch.develop", "develop")
 }
 
-fn gf_config() {
-    let repo = Repository::init(".").expect("Not a git-flow repository");
-    let config_l = repo.config().expect("Can not get local cofniguration");
-
-    let mut cfg = config_l.get_string("gitflow.branch.master").unwrap_or("".to_owned());
-    println!("Branch name for production releases: {}", cfg);
-    cfg = config_l.get_string("gitflow.branch.develop").unwrap_or("".to_owned());
-    println!("Branch name for \"next release\" development: {}", cfg);
-    cfg = config_l.get_string("gitflow.prefix.feature").unwrap_or("".to_owned());
-    println!("Feature branch prefix: {}", cfg);
-    cfg = config_l.get_string("gitflow.prefix.bugfix").unwrap_or("".to_owned());
-    println!("Bugfix branch prefix: {}", cfg);
-    cfg = config_l.get_string("gitflow.prefix.release").unwrap_or("".to_owned());
-    println!("Release branch prefix: {}", cfg);
-    cfg = config_l.get_string("gitflow.prefix.hotfix").unwrap_or("".to_owned());
-    println!("Hotfix branch prefix: {}", cfg);
-    cfg = config_l.get_string("gitflow.prefix.support").unwrap_or("".to_owned());
-    println!("Support branch prefix: {}", cfg);
-    cfg = config_l.get_string("gitflow.prefix.versiontag").unwrap_or("".to_owned());
-    println!("Version tag prefix: {}", cfg);
+fn gf_origin(repo: &Repository) -> String {
+    gf_config_or(repo, "gitflow.origin", "origin")
 }
 
-fn get_input(prompt: &str) -> String {
-    print!("{}: ", prompt);
-    io::stdout().flush().unwrap();
-    let mut input = String::new();
-    match io::stdin().read_line(&mut input) {
-        Ok(_goes_into_input_above) => {},
-        Err(_no_updates_is_fine) => {},
+// Multi-valued settings may be given several times or as a list separated
+// by commas or spaces.
+fn gf_config_list(repo: &Repository, key: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let cfg = match repo.config() {
+        Ok(cfg) => cfg,
+        Err(_) => return values,
+    };
+    let key_regex = format!("^{}$", key.replace('.', "\\."));
+    if let Ok(entries) = cfg.entries(Some(&key_regex)) {
+        for entry in &entries {
+            if let Some(value) = entry.ok().as_ref().and_then(|e| e.value()) {
+                values.extend(value
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|v| !v.is_empty())
+                    .map(|v| v.to_owned()));
+            }
+        }
     }
-    input.trim().to_string()
+    values
+}
+
+// Extra remotes which get the production branch and the version tags.
+fn gf_release_mirrors(repo: &Repository) -> Vec<String> {
+    let origin = gf_origin(repo);
+    let mut mirrors = gf_config_list(repo, "gitflow.release.remotes");
+    let mut seen = vec![origin];
+    mirrors.retain(|remote| {
+        let first = !seen.contains(remote);
+        seen.push(remote.to_owned());
+        first
+    });
+    mirrors
+}
+
+fn gf_branch_name(repo: &Repository, kind: &str, name: &str) -> String {
+    let prefix = gf_config_or(repo, &("gitflow.prefix.".to_owned() + kind), &(kind.to_owned() + "/"));
+    prefix + name
 }
 
-fn edit_msg(path: &str, default_msg: &str) -> String {
-    fs::write(path, default_msg).expect("Unable to write string");
+// The state git-flow keeps between runs lives under <gitdir>/gitflow.
+fn gf_state_path(repo: &Repository, name: &str) -> PathBuf {
+    repo.path().join("gitflow").join(name)
+}
 
-    let editor = env::var("EDITOR").unwrap_or("nvim".into());
-    Command::new(editor)
-        .arg(path)
-        .spawn()
-        .expect("Spawn nvim failed")
-        .wait()
-        .expect("Waiting nvim failed");
+fn remote_callbacks<'a>(repo: &Repository) -> RemoteCallbacks<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+    let config = repo.config().ok();
+    let mut tries = 0;
+
+    // Try the ssh agent and the configured credential helper before asking,
+    // and give up after a few rounds so a wrong password can't loop forever.
+    callbacks.credentials(move |url, username_from_url, allowed_types| {
+        tries += 1;
+        if tries > 3 {
+            return Err(Error::from_str("Authentication failed"));
+        }
+        if allowed_types.is_ssh_key() {
+            return Cred::ssh_key_from_agent(username_from_url.unwrap_or("git"));
+        }
+        if allowed_types.is_user_pass_plaintext() {
+            if let (1, Some(cfg)) = (tries, &config) {
+                if let Ok(cred) = Cred::credential_helper(cfg, url, username_from_url) {
+                    return Ok(cred);
+                }
+            }
+            let user = match username_from_url {
+                Some(user) => user.to_owned(),
+                None => get_input("Username"),
+            };
+            let pass = rpassword::read_password_from_tty(Some("Password: "))
+                .map_err(|e| Error::from_str(&e.to_string()))?;
+            return Cred::userpass_plaintext(&user, &pass);
+        }
+        Cred::default()
+    });
 
-    let mut msg = String::new();
-    let mut f = File::open(path).expect("Unable to open file");
-    f.read_to_string(&mut msg).expect("Unable to read string");
+    callbacks
+}
 
-    msg
+struct PushStatus {
+    refspec: String,
+    error: Option<String>,
 }
 
-fn create_tag(repo: &Repository, br: &str) -> Result<Oid, Error> {
-    let br_oid = repo.refname_to_id(&("refs/heads/".to_owned() + br))?;
-    let br_obj = repo.find_object(br_oid, None)?;
+fn push_dst(refspec: &str) -> &str {
+    refspec.rsplit(':').next().unwrap_or(refspec)
+}
 
-    // get a user input tag
-    let tagname = get_input("Input a tag name");
+// Push all refspecs in a single push. libgit2 can't ask the server for an
+// atomic push, so every ref gets its own status from the server instead.
+fn gf_push(repo: &Repository, remote_name: &str, refspecs: &[String]) -> Vec<PushStatus> {
+    let mut updates: HashMap<String, Option<String>> = HashMap::new();
+    let progress = progress::reporter("push", remote_name);
+    let written = Cell::new((0, 0, 0));
+    let result = repo.find_remote(remote_name).and_then(|mut remote| {
+        let mut callbacks = remote_callbacks(repo);
+        callbacks.pack_progress(|stage, current, total| {
+            let stage = match stage {
+                PackBuilderStage::AddingObjects => "counting",
+                PackBuilderStage::Deltafication => "compressing",
+            };
+            progress.update(stage, current, total, 0);
+        });
+        callbacks.push_transfer_progress(|current, total, bytes| {
+            written.set((current, total, bytes));
+            progress.update("writing", current, total, bytes);
+        });
+        callbacks.push_update_reference(|refname, status| {
+            updates.insert(refname.to_owned(), status.map(|s| s.to_owned()));
+            Ok(())
+        });
+        let mut options = PushOptions::new();
+        options.remote_callbacks(callbacks);
+        let specs: Vec<&str> = refspecs.iter().map(|s| s.as_str()).collect();
+        remote.push(&specs, Some(&mut options))
+    });
 
-    let tag_msg = &("Release version ".to_owned() + &tagname);
-    let msg = edit_msg(".git/TAG_EDITMSG", tag_msg);
+    let (current, total, bytes) = written.get();
+    progress.done(&format!("Wrote {}/{} objects in {}", current, total, progress::human_bytes(bytes)));
 
-    let sig = repo.signature()?;
-    let tag_oid = repo.tag(&tagname,
-        &br_obj,
-        &sig,
-        &msg,
-        true)?;
+    refspecs.iter().map(|refspec| {
+        let error = match &result {
+            Err(e) => Some(e.message().to_owned()),
+            Ok(()) => updates.get(push_dst(refspec)).cloned().unwrap_or(None),
+        };
+        PushStatus { refspec: refspec.to_owned(), error }
+    }).collect()
+}
 
-    Ok(tag_oid)
+fn read_pending_push(repo: &Repository) -> Vec<(String, String)> {
+    let content = fs::read_to_string(gf_state_path(repo, "push-pending")).unwrap_or_default();
+    content.lines().filter_map(|line| {
+        let mut fields = line.splitn(2, ' ');
+        Some((fields.next()?.to_owned(), fields.next()?.to_owned()))
+    }).collect()
 }
 
-fn gf_subcmd(cmd: &str, subcmd: &str, repo: &Repository,  base_br: &str, br: &str) -> Result<(), Error> {
-    let config_l = repo.config()?;
+fn write_pending_push(repo: &Repository, pending: &[(String, String)]) -> io::Result<()> {
+    let path = gf_state_path(repo, "push-pending");
+    if pending.is_empty() {
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        return Ok(());
+    }
 
-    let prefix_conf = &("gitflow.prefix.".to_owned() + cmd);
-    let prefix = config_l.get_string(prefix_conf)?;
-    let br_name = &(prefix + br);
+    fs::create_dir_all(path.parent().unwrap())?;
+    let content: String = pending.iter()
+        .map(|(remote, refspec)| format!("{} {}\n", remote, refspec))
+        .collect();
+    fs::write(&path, content)
+}
 
-    match subcmd {
-        "start" => create_checkout_branch(&repo, &br_name, Some(&base_br), None)?,
-        "finish" => {
-            let mut ff = true;
-            let refname = "refs/heads/".to_owned() + br_name;
-            let brrf = repo.find_reference(&refname)?;
-            let br_commit = repo.reference_to_annotated_commit(&brrf)?;
-            let base_br_ref = &("refs/heads/".to_owned() + base_br);
-            // set head to the base branch
-            repo.set_head(base_br_ref)?;
-            let analysis = repo.merge_analysis(&[&br_commit])?;
-            if analysis.0.is_fast_forward() {
-                println!("do the fast forward merging");
-                ff = true;
-            } else {
-                println!("do the normal merging");
-                ff = false;
-            }
+fn record_pending_push(repo: &Repository, remote_name: &str, statuses: &[PushStatus]) {
+    let mut pending = read_pending_push(repo);
+    pending.retain(|(remote, refspec)| {
+        remote != remote_name || !statuses.iter().any(|st| &st.refspec == refspec)
+    });
+    for st in statuses.iter().filter(|st| st.error.is_some()) {
+        pending.push((remote_name.to_owned(), st.refspec.to_owned()));
+    }
 
-            if cmd == "release" || cmd == "hotfix" {
-                ff = false;
-                merge_branch(&repo, "master", br_name, ff)?;
-                let _tag_oid = create_tag(&repo, "master")?;
-                //merge_tag(&repo, base_br, tag_oid)?;
-                merge_branch(&repo, base_br, "master", ff)?;
-            } else {
-                merge_branch(&repo, base_br, br_name, ff)?;
-            }
-            delete_branch(&repo, br_name)?;
+    if let Err(e) = write_pending_push(repo, &pending) {
+        say!("Unable to record the unpushed refs: {}", e);
+    }
+}
+
+// Pushes of a branch which is gone can never succeed any more.
+fn forget_pending_push(repo: &Repository, br_name: &str) {
+    let source = "refs/heads/".to_owned() + br_name;
+    let mut pending = read_pending_push(repo);
+    let count = pending.len();
+    pending.retain(|(_, refspec)| refspec.trim_start_matches('+').split(':').next() != Some(source.as_str()));
+    if pending.len() != count {
+        if let Err(e) = write_pending_push(repo, &pending) {
+            say!("Unable to record the unpushed refs: {}", e);
+        }
+    }
+}
+
+fn gf_push_refs(repo: &Repository, remote_name: &str, refspecs: &[String]) -> bool {
+    gf_push_statuses(repo, remote_name, refspecs).iter().all(|st| st.error.is_none())
+}
+
+// Push and report the outcome of each refspec.
+fn gf_push_statuses(repo: &Repository, remote_name: &str, refspecs: &[String]) -> Vec<PushStatus> {
+    say!("Pushing to {}", remote_name);
+    let statuses = gf_push(repo, remote_name, refspecs);
+    for st in &statuses {
+        output::record_push(remote_name, &st.refspec, st.error.as_deref());
+        match &st.error {
+            None => say!("  {}: ok", st.refspec),
+            Some(e) => say!("! {}: {}", st.refspec, e),
+        }
+    }
+    record_pending_push(repo, remote_name, &statuses);
+
+    if statuses.iter().any(|st| st.error.is_some()) {
+        say!("Run `git flow push-pending` to push the rejected refs again");
+    }
+    statuses
+}
+
+fn gf_push_pending(repo: &Repository) -> bool {
+    let pending = read_pending_push(repo);
+    if pending.is_empty() {
+        say!("Nothing to push");
+        return true;
+    }
+
+    let mut remotes: Vec<&str> = pending.iter().map(|(remote, _)| remote.as_str()).collect();
+    remotes.sort();
+    remotes.dedup();
+
+    let mut pushed = true;
+    for remote in remotes {
+        let refspecs: Vec<String> = pending.iter()
+            .filter(|(r, _)| r == remote)
+            .map(|(_, refspec)| refspec.to_owned())
+            .collect();
+        pushed &= gf_push_refs(repo, remote, &refspecs);
+    }
+
+    pushed
+}
+
+// Push to several remotes one after another and sum up how each went.
+fn gf_push_remotes(repo: &Repository, pushes: &[(String, Vec<String>)]) -> bool {
+    let results: Vec<(&str, bool)> = pushes.iter()
+        .map(|(remote, refspecs)| (remote.as_str(), gf_push_refs(repo, remote, refspecs)))
+        .collect();
+
+    if results.len() > 1 {
+        say!("Push summary:");
+        for (remote, pushed) in &results {
+            say!("  {}: {}", remote, if *pushed { "ok" } else { "failed" });
+        }
+    }
+
+    results.iter().all(|(_, pushed)| *pushed)
+}
+
+// Without a path the repository around the current directory is set up,
+// a new one is only created when there is none. What is configured
+// already stays, and an unconfigured production branch is the one HEAD
+// is on.
+fn gf_init(path: Option<&str>) -> Result<(), Error> {
+    let repo = match path {
+        Some(path) => Repository::open(path).or_else(|_| Repository::init(path))?,
+        None => gf_open_repo().or_else(|_| Repository::init("."))?,
+    };
+    let config = repo.config()?;
+    let head_branch = repo.find_reference("HEAD").ok()
+        .and_then(|head| head.symbolic_target().and_then(|target| target.strip_prefix("refs/heads/")).map(str::to_owned));
+    let master = config.get_string("gitflow.branch.master").ok()
+        .or(head_branch)
+        .unwrap_or_else(|| "master".to_owned());
+    let develop = gf_develop(&repo);
+    let master_ref = "refs/heads/".to_owned() + &master;
+
+    // create an initial commit for master branch
+    let unborn = repo.head().err().is_some_and(|e| e.code() == ErrorCode::UnbornBranch);
+    if unborn && repo.refname_to_id(&master_ref).is_err() {
+        create_initial_commit(&repo, &master_ref)?;
+        repo.set_head(&master_ref)?;
+    }
+    let master_oid = repo.refname_to_id(&master_ref)
+        .map_err(|_| Error::from_str(&format!("The production branch {} does not exist", master)))?;
+
+    // git checkout -b develop master
+    if repo.find_branch(&develop, BranchType::Local).is_err() {
+        create_checkout_branch(&repo, &develop, None, Some(&master_oid.to_string()))?;
+    }
+
+    let mut config_l = repo.config()?.open_level(ConfigLevel::Local)?;
+    for (key, value) in [
+        ("gitflow.branch.master", master.as_str()),
+        ("gitflow.branch.develop", develop.as_str()),
+        ("gitflow.prefix.feature", "feature/"),
+        ("gitflow.prefix.release", "release/"),
+        ("gitflow.prefix.hotfix", "hotfix/"),
+        ("gitflow.prefix.bugfix", "bugfix/"),
+        ("gitflow.prefix.support", "support/"),
+        ("gitflow.prefix.versiontag", ""),
+    ] {
+        if config.get_entry(key).is_err() {
+            config_l.set_str(key, value)?;
         }
-        _ => println!("Not implement {} for {}", subcmd, cmd),
     }
 
     Ok(())
 }
 
-fn gf_list_branch(gf_br: &str) {
-    let p = &(".git/refs/heads/".to_owned() + gf_br);
-    let gf_br_path = Path::new(p);
-    if !gf_br_path.exists() {
-        println!("No {} branches exist.", gf_br);
+// Which configuration file the config commands read and write. Without
+// a scope reads see the merged configuration and writes go to the
+// repository.
+enum ConfigScope {
+    Default,
+    Local,
+    Global,
+    System,
+    File(PathBuf),
+}
+
+fn scope_name(level: ConfigLevel) -> &'static str {
+    match level {
+        ConfigLevel::ProgramData | ConfigLevel::System => "system",
+        ConfigLevel::XDG | ConfigLevel::Global => "global",
+        ConfigLevel::Local => "local",
+        _ => "command",
+    }
+}
+
+fn gf_config_open(repo: Option<&Repository>, scope: &ConfigScope, write: bool) -> Result<Config, Error> {
+    let not_a_repo = || Error::from_str("Not a git repository, use --global, --system or --file outside of one");
+    match scope {
+        ConfigScope::Default if !write => repo.ok_or_else(not_a_repo)?.config(),
+        ConfigScope::Default | ConfigScope::Local => repo.ok_or_else(not_a_repo)?.config()?.open_level(ConfigLevel::Local),
+        ConfigScope::Global => {
+            let path = Config::find_global().or_else(|_| {
+                env::var_os("HOME").map(|home| Path::new(&home).join(".gitconfig"))
+                    .ok_or_else(|| Error::from_str("Can't find the global configuration file"))
+            })?;
+            Config::open(&path)
+        }
+        ConfigScope::System => Config::open(&Config::find_system()?),
+        ConfigScope::File(path) => Config::open(path),
+    }
+}
+
+fn gf_config(config: &Config) {
+    let get = |key: &str| config.get_string(key).unwrap_or_default();
+
+    if !output::is_human() {
+        output::emit(&json!({
+            "branch": {
+                "master": get("gitflow.branch.master"),
+                "develop": get("gitflow.branch.develop"),
+            },
+            "prefix": {
+                "feature": get("gitflow.prefix.feature"),
+                "bugfix": get("gitflow.prefix.bugfix"),
+                "release": get("gitflow.prefix.release"),
+                "hotfix": get("gitflow.prefix.hotfix"),
+                "support": get("gitflow.prefix.support"),
+                "versiontag": get("gitflow.prefix.versiontag"),
+            },
+        }));
         return;
     }
-    let paths = Path::read_dir(gf_br_path).unwrap();
 
-    let mut cur_br = String::new();
-    let mut f = File::open(".git/HEAD").expect("Unable to open file");
-    f.read_to_string(&mut cur_br).expect("Unable to read string");
-    cur_br = cur_br.replace(&("ref: refs/heads/".to_owned() + gf_br + "/"), "");
+    say!("Branch name for production releases: {}", get("gitflow.branch.master"));
+    say!("Branch name for \"next release\" development: {}", get("gitflow.branch.develop"));
+    say!("Feature branch prefix: {}", get("gitflow.prefix.feature"));
+    say!("Bugfix branch prefix: {}", get("gitflow.prefix.bugfix"));
+    say!("Release branch prefix: {}", get("gitflow.prefix.release"));
+    say!("Hotfix branch prefix: {}", get("gitflow.prefix.hotfix"));
+    say!("Support branch prefix: {}", get("gitflow.prefix.support"));
+    say!("Version tag prefix: {}", get("gitflow.prefix.versiontag"));
+}
 
-    for path in paths {
-        let file_name = path.unwrap().file_name();
-        let br = file_name.to_str().unwrap();
-        if br == cur_br.trim() {
-            println!("* {}", br);
+// Accept the short names used in the messages above as well as full keys.
+fn gf_config_key(name: &str) -> Option<String> {
+    match name {
+        "master" | "develop" => Some(format!("gitflow.branch.{}", name)),
+        "feature" | "bugfix" | "release" | "hotfix" | "support" | "versiontag" => {
+            Some(format!("gitflow.prefix.{}", name))
+        }
+        "origin" => Some("gitflow.origin".to_owned()),
+        name if name.starts_with("gitflow.") => Some(name.to_owned()),
+        _ => None,
+    }
+}
+
+// Checks which need a repository are left out when there is none, as for
+// --global outside of one.
+fn gf_config_validate(repo: Option<&Repository>, key: &str, value: &str) -> Result<(), Error> {
+    if let Some(which) = key.strip_prefix("gitflow.branch.") {
+        if !Branch::name_is_valid(value)? {
+            return Err(Error::from_str(&format!("'{}' is not a valid branch name", value)));
+        }
+        if let Some(repo) = repo {
+            let other = match which {
+                "master" => gf_develop(repo),
+                _ => gf_master(repo),
+            };
+            if (which == "master" || which == "develop") && other == value {
+                return Err(Error::from_str(&format!("'{}' is already used as the {} branch", value,
+                    if which == "master" { "develop" } else { "master" })));
+            }
+        }
+    } else if let Some(kind) = key.strip_prefix("gitflow.prefix.") {
+        if kind != "versiontag" && !value.is_empty() && !value.ends_with('/') {
+            return Err(Error::from_str(&format!("The {} prefix must end with '/' or be empty", kind)));
+        }
+        let refname = if kind == "versiontag" {
+            format!("refs/tags/{}1.0", value)
         } else {
-            println!("  {}", br);
+            format!("refs/heads/{}name", value)
+        };
+        if !Reference::is_valid_name(&refname) {
+            return Err(Error::from_str(&format!("'{}' can't be used in a ref name", value)));
         }
+    } else if key == "gitflow.origin" && repo.is_some_and(|repo| repo.find_remote(value).is_err()) {
+        say!("Warning: there is no remote named {} yet", value);
     }
+
+    Ok(())
 }
 
-fn gf_diff_branches(old: &str, new: Option<&str>) {
-    let repo = Repository::open(".").expect("Not a git repository");
-    let newtree: Object;
-    let oldtree = tree_to_treeish(&repo, old)
-        .expect("Get old tree failed")
-        .unwrap();
-    if new == None {
-        let headref = repo.head().expect("Get head reference failed");
-        let headname = headref.name().unwrap();
-        newtree = tree_to_treeish(&repo, headname)
-            .expect("Get old tree failed")
-            .unwrap();
-    } else {
-        newtree = tree_to_treeish(&repo, new.unwrap())
-            .expect("Get old tree failed")
-            .unwrap();
-    }
-
-    let diff = repo.diff_tree_to_tree(Some(oldtree.as_tree().unwrap()), Some(newtree.as_tree().unwrap()), None)
-        .expect("Get diff failed");
-
-    let mut last_color = None;
-    diff.print(DiffFormat::Patch, |_delta, _hunk, line| {
-        let next = match line.origin() {
-            '+' => Some(GREEN),
-            '-' => Some(RED),
-            '>' => Some(GREEN),
-            '<' => Some(RED),
-            'F' => Some(BOLD),
-            'H' => Some(CYAN),
-            _ => None,
+fn gf_config_get(config: &Config, key: &str) -> Result<(), Error> {
+    let value = config.get_string(key)?;
+    say!("{}", value);
+    output::emit(&json!({ "key": key, "value": value }));
+
+    Ok(())
+}
+
+fn gf_config_show_all(config: &Config, scope: &ConfigScope) -> Result<(), Error> {
+    let mut values = Vec::new();
+    let entries = config.entries(Some("gitflow\\..*"))?;
+    for entry in &entries {
+        let entry = entry?;
+        // a single opened file doesn't know which scope it belongs to
+        let scope = match scope {
+            ConfigScope::Default => scope_name(entry.level()),
+            ConfigScope::Local => "local",
+            ConfigScope::Global => "global",
+            ConfigScope::System => "system",
+            ConfigScope::File(_) => "file",
         };
-        if next != last_color {
-            if last_color == Some(BOLD) || next == Some(BOLD) {
-                print!("{}", RESET);
+        if let (Some(name), Some(value)) = (entry.name(), entry.value()) {
+            say!("{}\t{}={}", scope, name, value);
+            values.push(json!({ "scope": scope, "key": name, "value": value }));
+        }
+    }
+    output::emit(&json!(values));
+
+    Ok(())
+}
+
+// Move the branches of a kind to a new prefix, after the prefix changed.
+fn gf_rename_prefix(repo: &Repository, kind: &str, old_prefix: &str, new_prefix: &str, ask: bool) -> Result<(), Error> {
+    // the branches gf_kind_of gave to kind while old_prefix was in effect,
+    // the other kinds' prefixes haven't changed
+    let was_of_kind = |br: &str| {
+        !old_prefix.is_empty() && br.starts_with(old_prefix)
+            && FLOW_KINDS.iter().filter(|other| **other != kind).all(|other| {
+                let prefix = gf_branch_name(repo, other, "");
+                prefix.len() <= old_prefix.len() || !br.starts_with(&prefix)
+            })
+    };
+    let (master, develop) = (gf_master(repo), gf_develop(repo));
+    let mut orphans = Vec::new();
+    for branch in repo.branches(Some(BranchType::Local))? {
+        if let Some(br) = branch?.0.name()? {
+            if was_of_kind(br) && !br.starts_with(new_prefix) && br != master && br != develop {
+                orphans.push(br.to_owned());
             }
-            print!("{}", next.unwrap_or(RESET));
-            last_color = next;
         }
+    }
+    orphans.sort();
+    if orphans.is_empty() {
+        return Ok(());
+    }
 
-        match line.origin() {
-            '+' | '-' | ' ' => print!("{}", line.origin()),
-            _ => {}
+    say!("Warning: these {} branches would no longer be seen as {} branches:", orphans.len(), kind);
+    for br in &orphans {
+        say!("    {}", br);
+    }
+    if ask && !get_input(&format!("Rename them to {}...? [y/N]", new_prefix)).to_lowercase().starts_with('y') {
+        return Ok(());
+    }
+    for br in &orphans {
+        let new_name = new_prefix.to_owned() + &br[old_prefix.len()..];
+        // first, libgit2 renames every "branch.<br>." it finds in the
+        // config, also inside gitflow.branch.<br>.base
+        rename_base(repo, br, &new_name)?;
+        repo.find_branch(br, BranchType::Local)?.rename(&new_name, false)?;
+        say!("Renamed {} to {}", br, new_name);
+    }
+
+    Ok(())
+}
+
+// Offer to rename the branches a changed prefix leaves behind. The
+// change may be shadowed by another scope, so it is the prefix in effect
+// before and after which counts.
+fn gf_config_changed(repo: Option<&Repository>, key: &str, old_prefix: Option<String>, ask: bool) -> Result<(), Error> {
+    let kind = key.strip_prefix("gitflow.prefix.").filter(|kind| FLOW_KINDS.contains(kind));
+    if let (Some(repo), Some(kind), Some(old_prefix)) = (repo, kind, old_prefix) {
+        let new_prefix = gf_branch_name(repo, kind, "");
+        if old_prefix != new_prefix {
+            gf_rename_prefix(repo, kind, &old_prefix, &new_prefix, ask)?;
         }
-        print!("{}", str::from_utf8(line.content()).unwrap());
-        true
-    }).expect("Print diffs failed");
+    }
+
+    Ok(())
 }
 
-fn gf_publish(br_name: Option<&str>, user: &str, pass: &str) {
-    // Urgly, TODO 2. get remote name from repository?
-    let remote_name = "origin";
-    //let remote_branch = br_name.unwrap_or("master");
-    let repo = Repository::open(".").expect("Not a git repository");
-    let mut remote = repo.find_remote(remote_name).expect("Find remote name failed");
+fn gf_effective_prefix(repo: Option<&Repository>, key: &str) -> Option<String> {
+    let kind = key.strip_prefix("gitflow.prefix.").filter(|kind| FLOW_KINDS.contains(kind))?;
+    repo.map(|repo| gf_branch_name(repo, kind, ""))
+}
 
-    let mut callbacks = RemoteCallbacks::new();
-    /* Push */
-    let mut options = PushOptions::new();
+fn gf_config_set(repo: Option<&Repository>, scope: &ConfigScope, key: &str, value: &str, rename: bool) -> Result<(), Error> {
+    // only a setting for this repository is checked against it, and only
+    // that one gets its branch created
+    let local_repo = repo.filter(|_| matches!(scope, ConfigScope::Default | ConfigScope::Local));
+    gf_config_validate(local_repo, key, value)?;
+    let mut config = gf_config_open(repo, scope, true)?;
+    let old_prefix = gf_effective_prefix(repo, key);
+
+    if let (Some(repo), Some(which)) = (local_repo, key.strip_prefix("gitflow.branch.")) {
+        if repo.find_branch(value, BranchType::Local).is_err() {
+            // start the new branch where the old one is, or at HEAD
+            let old = gf_config_or(repo, key, which);
+            let commit = match repo.find_branch(&old, BranchType::Local) {
+                Ok(branch) => branch.get().peel_to_commit(),
+                Err(_) => repo.head().and_then(|head| head.peel_to_commit()),
+            }.map_err(|_| Error::from_str(&format!("Branch {} does not exist and there is no commit to create it at", value)))?;
+            repo.branch(value, &commit, false)?;
+            say!("Created branch {} at {}", value, commit.as_object().short_id()?.as_str().unwrap_or(""));
+        }
+    }
 
-    // TODO 1. get userpass from configuration or in memory if it was there
-    callbacks.credentials(|_url, _username_from_url, _allowed_types| {
-        Cred::userpass_plaintext(user, pass)
-    });
-    //callbacks.push_update_reference(|refname, status| {
-    //    Ok(())
-    //});
-    options.remote_callbacks(callbacks);
-    // push the specified branch
-    let br = "refs/heads/".to_owned() + br_name.unwrap_or("master");
-    remote.push(&[&br], Some(&mut options)).unwrap();
-}
-
-fn gf_track(br_name: &str) {
-    let remote_name = "origin";
-    let repo = Repository::open(".").expect("Not a git repository");
-    let mut remote = repo.find_remote(remote_name).expect("Find remote name failed");
-
-    let fetch_commit = do_fetch(&repo, &[&br_name], &mut remote).expect("do_fetch failed");
-    do_merge(&repo, &br_name, fetch_commit).expect("do_merge failed");
-}
-
-fn gf_rebase(br_name: Option<&str>, _opt: Option<&str>) {
-    // git rebase develop [--interactive|--rebase-merges]
-
-    let repo = Repository::open(".").expect("Not a git repository");
-
-    let head_target = repo.head().unwrap().target().unwrap();
-    let tip = repo.find_commit(head_target).unwrap();
-    let sig = tip.author();
-
-    if let Some(br_name) = br_name {
-        let mut opts: RebaseOptions<'_> = Default::default();
-
-        let br_name = "refs/heads/".to_owned() + br_name;
-        let head = repo.find_reference(&br_name).unwrap();
-        let branch = repo.reference_to_annotated_commit(&head).unwrap();
-        let develop = repo.find_reference("refs/heads/develop").unwrap();
-        let upstream = repo.reference_to_annotated_commit(&develop).unwrap();
-        let mut rebase = repo
-            .rebase(Some(&branch), Some(&upstream), None, Some(&mut opts))
-            .unwrap();
-
-        let mut rebase_len = rebase.len();
-        while rebase_len > 0 {
-            match rebase.next().unwrap() {
-                Ok(_) => rebase.commit(None, &sig, None).unwrap(),
-                Err(_) => break,
-            };
-            rebase_len -= 1;
+    config.set_str(key, value)?;
+
+    gf_config_changed(repo, key, old_prefix, !rename)
+}
+
+fn gf_config_unset(repo: Option<&Repository>, scope: &ConfigScope, key: &str) -> Result<(), Error> {
+    let mut config = gf_config_open(repo, scope, true)?;
+    let old_prefix = gf_effective_prefix(repo, key);
+
+    config.remove(key)?;
+
+    gf_config_changed(repo, key, old_prefix, true)
+}
+
+fn get_input(prompt: &str) -> String {
+    if output::is_human() {
+        print!("{}: ", prompt);
+        io::stdout().flush().unwrap();
+    } else {
+        eprint!("{}: ", prompt);
+    }
+    let mut input = String::new();
+    match io::stdin().read_line(&mut input) {
+        Ok(_goes_into_input_above) => {},
+        Err(_no_updates_is_fine) => {},
+    }
+    input.trim().to_string()
+}
+
+// The editor the way git picks it.
+fn git_editor(repo: &Repository) -> String {
+    env::var("GIT_EDITOR").ok()
+        .or_else(|| repo.config().ok().and_then(|config| config.get_string("core.editor").ok()))
+        .or_else(|| env::var("VISUAL").ok())
+        .or_else(|| env::var("EDITOR").ok())
+        .filter(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_owned())
+}
+
+// Let the user edit text in <gitdir>/gitflow/<file>, with help shown as comment
+// lines. The comment lines and surrounding blank lines are taken out of
+// what comes back.
+fn edit_text(repo: &Repository, file: &str, text: &str, help: &str) -> Result<String, Error> {
+    // kept out of where git looks for MERGE_MSG and SQUASH_MSG, which it
+    // would take up as the template of the next commit
+    let path = gf_state_path(repo, file);
+    let mut content = text.trim_end().to_owned() + "\n\n";
+    for line in help.lines() {
+        content += &if line.is_empty() { "#\n".to_owned() } else { format!("# {}\n", line) };
+    }
+    fs::create_dir_all(path.parent().unwrap())
+        .and_then(|_| fs::write(&path, content))
+        .map_err(|e| Error::from_str(&format!("Can't write {}: {}", path.display(), e)))?;
+
+    // like git, through the shell so the editor can come with arguments
+    let editor = git_editor(repo);
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(&editor)
+        .arg(&path)
+        .status()
+        .map_err(|e| Error::from_str(&format!("Can't run the editor {}: {}", editor, e)))?;
+    if !status.success() {
+        return Err(Error::from_str(&format!("The editor {} failed, aborting", editor)));
+    }
+
+    let edited = fs::read_to_string(&path)
+        .map_err(|e| Error::from_str(&format!("Can't read {}: {}", path.display(), e)))?;
+    fs::remove_file(&path).unwrap_or(());
+    let lines: Vec<&str> = edited.lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(str::trim_end)
+        .collect();
+    Ok(lines.join("\n").trim_matches('\n').to_owned())
+}
+
+// A commit or tag message, an empty one aborts.
+fn edit_msg(repo: &Repository, file: &str, default_msg: &str, help: &str) -> Result<String, Error> {
+    let help = help.to_owned() + "\nLines starting with '#' will be ignored, and an empty message aborts.";
+    let msg = edit_text(repo, file, default_msg, help.trim_start())?;
+    if msg.is_empty() {
+        return Err(Error::from_str("Aborting because of an empty message"));
+    }
+    Ok(msg + "\n")
+}
+
+// Asked before merging, so the merge messages can name the tag.
+fn ask_tag_name(repo: &Repository, kind: &str) -> Result<String, Error> {
+    // get a user input tag
+    let tagname = get_input("Input a tag name");
+    let tagname = hooks::filter(repo, &format!("filter-flow-{}-finish-tag-name", kind), &tagname, &[])?;
+    let refname = "refs/tags/".to_owned() + &tagname;
+    if !Reference::is_valid_name(&refname) {
+        return Err(Error::from_str(&format!("{} is not a valid tag name", tagname)));
+    }
+    if repo.find_reference(&refname).is_ok() {
+        return Err(Error::from_str(&format!("The tag {} already exists", tagname)));
+    }
+    Ok(tagname)
+}
+
+// Also asked before merging, so giving up on it changes nothing.
+fn ask_tag_message(repo: &Repository, kind: &str, tagname: &str) -> Result<String, Error> {
+    let tag_msg = &("Release version ".to_owned() + tagname);
+    let tag_msg = hooks::filter(repo, &format!("filter-flow-{}-finish-tag-message", kind), tag_msg, &[tagname])?;
+    edit_msg(repo, "TAG_EDITMSG", &tag_msg, &format!("Please enter the message for the tag {}.", tagname))
+}
+
+fn create_tag(repo: &Repository, br: &str, tagname: &str, msg: &str) -> Result<String, Error> {
+    let br_oid = repo.refname_to_id(&("refs/heads/".to_owned() + br))?;
+    let br_obj = repo.find_object(br_oid, None)?;
+
+    let sig = repo.signature()?;
+    repo.tag(tagname,
+        &br_obj,
+        &sig,
+        msg,
+        false)?;
+
+    Ok(tagname.to_owned())
+}
+
+// How finish merges into the base branch when a fast forward is possible.
+#[derive(Clone, Copy, PartialEq)]
+enum MergePolicy {
+    Ff,
+    NoFf,
+    FfOnly,
+}
+
+impl MergePolicy {
+    fn name(self) -> &'static str {
+        match self {
+            MergePolicy::Ff => "ff",
+            MergePolicy::NoFf => "no-ff",
+            MergePolicy::FfOnly => "ff-only",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<MergePolicy> {
+        match name {
+            "ff" => Some(MergePolicy::Ff),
+            "no-ff" => Some(MergePolicy::NoFf),
+            "ff-only" => Some(MergePolicy::FfOnly),
+            _ => None,
         }
-        rebase.finish(None).unwrap();
     }
 }
 
-fn gf_run() {
-    let matches = App::new("git-flow")
-        .version("0.5.0")
-        .author("Jason Wang <wang_borong@163.com>")
-        .about("Workflow in git")
-        // Init subcommand
-        .subcommand(SubCommand::with_name("init")
-            .about("Setup a git repository for git flow usage.")
-            .arg(Arg::with_name("init_path")
-                .help("Path to be initialized")))
-        // Config subcommand
+// gitflow.<kind>.finish.no-ff first, then merge.ff the way git merge reads it.
+fn gf_merge_policy(repo: &Repository, kind: &str) -> MergePolicy {
+    let config = match repo.config() {
+        Ok(config) => config,
+        Err(_) => return MergePolicy::Ff,
+    };
+    if let Ok(no_ff) = config.get_bool(&format!("gitflow.{}.finish.no-ff", kind)) {
+        return if no_ff { MergePolicy::NoFf } else { MergePolicy::Ff };
+    }
+    match config.get_string("merge.ff").map(|value| value.to_lowercase()).as_deref() {
+        Ok("only") => MergePolicy::FfOnly,
+        Ok("false") | Ok("no") | Ok("off") | Ok("0") => MergePolicy::NoFf,
+        _ => MergePolicy::Ff,
+    }
+}
+
+struct FinishOpts {
+    push: bool,
+    // None leaves it to gitflow.<kind>.finish.squash
+    squash: Option<bool>,
+    rebase: bool,
+    // None leaves it to gf_merge_policy
+    merge: Option<MergePolicy>,
+}
+
+// A finish --rebase which stopped on a conflict is remembered in
+// <gitdir>/gitflow/finish as "key value" lines, for finish --continue.
+fn write_finish_state(repo: &Repository, kind: &str, br: &str, opts: &FinishOpts) -> io::Result<()> {
+    let path = gf_state_path(repo, "finish");
+    fs::create_dir_all(path.parent().unwrap())?;
+    let mut content = format!("kind {}\nname {}\npush {}\n", kind, br, opts.push);
+    if let Some(squash) = opts.squash {
+        content += &format!("squash {}\n", squash);
+    }
+    if let Some(merge) = opts.merge {
+        content += &format!("merge {}\n", merge.name());
+    }
+    fs::write(path, content)
+}
+
+fn read_finish_state(repo: &Repository) -> Option<(String, String, FinishOpts)> {
+    let content = fs::read_to_string(gf_state_path(repo, "finish")).ok()?;
+    let values: HashMap<&str, &str> = content.lines().filter_map(|line| {
+        let mut words = line.splitn(2, ' ');
+        Some((words.next()?, words.next()?))
+    }).collect();
+    let opts = FinishOpts {
+        push: values.get("push") == Some(&"true"),
+        squash: values.get("squash").map(|squash| *squash == "true"),
+        rebase: false,
+        merge: values.get("merge").and_then(|merge| MergePolicy::from_name(merge)),
+    };
+    Some((values.get("kind")?.to_string(), values.get("name")?.to_string(), opts))
+}
+
+fn remove_finish_state(repo: &Repository) {
+    fs::remove_file(gf_state_path(repo, "finish")).unwrap_or(());
+}
+
+fn rebase_stopped(kind: &str, message: &str, command: &str) -> Error {
+    Error::from_str(&format!("{}\n\
+                              Resolve the conflicts, git add them and run \"git flow {} {} --continue\",\n\
+                              run \"git flow {} rebase --skip\" to leave the commit out,\n\
+                              or \"git flow {} rebase --abort\" to go back", message, kind, command, kind, kind))
+}
+
+// rebase.autoSquash, for rebases without --autosquash or --no-autosquash.
+fn gf_autosquash(repo: &Repository) -> bool {
+    repo.config().and_then(|config| config.get_bool("rebase.autoSquash")).unwrap_or(false)
+}
+
+// Rebase br_name onto the tip of base_br.
+fn gf_rebase(repo: &Repository, br_name: &str, base_br: &str, opts: &rebase::Options) -> Result<rebase::Outcome, Error> {
+    check_not_elsewhere(repo, br_name)?;
+    let base = repo.refname_to_id(&("refs/heads/".to_owned() + base_br))?;
+    rebase::start(repo, &("refs/heads/".to_owned() + br_name), base, base, opts)
+}
+
+// Pick up a finish --rebase after its conflicts were resolved.
+fn gf_finish_continue(repo: &Repository, kind: &str) -> Result<(String, bool), Error> {
+    let (state_kind, br, opts) = read_finish_state(repo)
+        .ok_or_else(|| Error::from_str("There is no finish to continue"))?;
+    if state_kind != kind {
+        return Err(Error::from_str(&format!("The finish to continue is one of a {}", state_kind)));
+    }
+    if rebase::in_progress(repo) {
+        if let rebase::Outcome::Stopped(message) = rebase::resume(repo)? {
+            return Err(rebase_stopped(kind, &message, "finish"));
+        }
+    }
+    remove_finish_state(repo);
+
+    let base_br = gf_branch_base(repo, kind, &gf_branch_name(repo, kind, &br));
+    gf_finish(repo, kind, &base_br, &br, &opts).map(|pushed| (br, pushed))
+}
+
+// Free text as a branch name: anything git doesn't allow in a ref becomes
+// a dash.
+fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.trim().chars() {
+        let c = if c.is_alphanumeric() || "._/-".contains(c) { c } else { '-' };
+        let last = slug.chars().last();
+        // no "..", "//", "/." or runs of dashes
+        if (c == '-' || c == '.' || c == '/') && last == Some(c)
+            || c == '.' && last == Some('/')
+            || c == '-' && last.is_none() {
+            continue;
+        }
+        slug.push(c);
+    }
+    let mut slug = slug.trim_matches(['-', '.', '/']).to_owned();
+    while slug.ends_with(".lock") {
+        slug.truncate(slug.len() - ".lock".len());
+        slug = slug.trim_end_matches(['-', '.', '/']).to_owned();
+    }
+    slug
+}
+
+// Why git would refuse the name, for the first rule it breaks.
+fn refname_problem(name: &str) -> &'static str {
+    if name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        "it contains spaces or control characters"
+    } else if name.chars().any(|c| "~^:?*[\\".contains(c)) {
+        "it contains one of ~ ^ : ? * [ \\"
+    } else if name.contains("..") || name.contains("@{") || name.contains("//") {
+        "it contains \"..\", \"@{\" or \"//\""
+    } else if name.starts_with('-') || name.starts_with('.') || name.contains("/.") {
+        "a part of it starts with \"-\" or \".\""
+    } else if name.ends_with('/') || name.ends_with('.') || name.ends_with(".lock") {
+        "it ends with \"/\", \".\" or \".lock\""
+    } else {
+        "git doesn't allow it in a ref name"
+    }
+}
+
+// Names have to make valid refs, may have a length limit and a pattern
+// per kind and must not be taken yet.
+fn gf_check_name(repo: &Repository, kind: &str, name: &str) -> Result<(), Error> {
+    let reject = |why: String| Err(Error::from_str(&format!("The {} name '{}' is rejected: {}", kind, name, why)));
+    let suggest = || {
+        let slug = slugify(name);
+        if slug.is_empty() || slug == name {
+            String::new()
+        } else {
+            format!(", try '{}' or --slugify", slug)
+        }
+    };
+
+    if name.is_empty() {
+        return reject("it is empty".to_owned());
+    }
+    let br_name = gf_branch_name(repo, kind, name);
+    if !Branch::name_is_valid(&br_name)? {
+        return reject(format!("{}{}", refname_problem(&br_name), suggest()));
+    }
+
+    let config = repo.config()?;
+    let max_length = config.get_i64(&format!("gitflow.{}.name.maxlength", kind))
+        .or_else(|_| config.get_i64("gitflow.name.maxlength"));
+    if let Ok(max_length) = max_length {
+        if name.chars().count() as i64 > max_length {
+            return reject(format!("it is longer than {} characters", max_length));
+        }
+    }
+
+    let pattern_key = format!("gitflow.{}.name.pattern", kind);
+    if let Ok(pattern) = config.get_string(&pattern_key) {
+        let re = regex::Regex::new(&format!("^(?:{})$", pattern))
+            .map_err(|e| Error::from_str(&format!("{} is not a valid regex: {}", pattern_key, e)))?;
+        if !re.is_match(name) {
+            return reject(format!("it doesn't match {} ({})", pattern, pattern_key));
+        }
+    }
+
+    if repo.find_branch(&br_name, BranchType::Local).is_ok() {
+        return reject(format!("branch {} already exists", br_name));
+    }
+    let remote = gf_origin(repo);
+    if repo.find_branch(&format!("{}/{}", remote, br_name), BranchType::Remote).is_ok() {
+        return reject(format!("branch {} already exists on {}", br_name, remote));
+    }
+
+    Ok(())
+}
+
+// The branch a base given to start stands for: itself, or the local branch
+// of a remote one.
+fn gf_base_of_ref(repo: &Repository, base: &str) -> Option<String> {
+    if repo.find_branch(base, BranchType::Local).is_ok() {
+        return Some(base.to_owned());
+    }
+    let branch = repo.find_branch(base, BranchType::Remote).ok()?;
+    let remote = repo.branch_remote_name(branch.get().name()?).ok()?;
+    let remote = remote.as_str()?;
+    base.strip_prefix(remote).and_then(|br| br.strip_prefix('/')).map(str::to_owned)
+}
+
+// Warn about a start point which doesn't come from where the kind usually
+// starts, it brings its own history along when finished.
+fn gf_check_base(repo: &Repository, kind: &str, base: &str, oid: Oid) -> Result<(), Error> {
+    let expected = gf_base_branch(repo, kind);
+    let expected_oid = match repo.refname_to_id(&("refs/heads/".to_owned() + &expected)) {
+        Ok(oid) => oid,
+        Err(_) => return Ok(()),
+    };
+    if oid != expected_oid && !repo.graph_descendant_of(expected_oid, oid)? {
+        say!("Warning: {} is not contained in {}, the {} will carry its extra commits along", base, expected, kind);
+    }
+    Ok(())
+}
+
+// Only features and bugfixes are finished into the branch they started
+// from. A release or hotfix goes into master and back into develop, wherever
+// it started.
+fn gf_keeps_base(kind: &str) -> bool {
+    kind == "feature" || kind == "bugfix"
+}
+
+// Start br from base, which can be a branch, a tag, a commit or a remote
+// branch, and remember the branch it is to be finished into. With a
+// worktree path it is checked out there instead of here.
+fn gf_start(repo: &Repository, kind: &str, base: &str, br: &str, worktree: Option<&Path>) -> Result<(), Error> {
+    let br_name = &gf_branch_name(repo, kind, br);
+    let commit = repo.revparse_single(base)
+        .and_then(|obj| obj.peel_to_commit())
+        .map_err(|_| Error::from_str(&format!("{} is not a branch, tag or commit", base)))?;
+    gf_check_base(repo, kind, base, commit.id())?;
+
+    match worktree {
+        Some(path) => {
+            repo.branch(br_name, &commit, false)?;
+            worktree::add(repo, br_name, path)?;
+        }
+        None => create_checkout_branch(repo, br_name, None, Some(&commit.id().to_string()))?,
+    }
+
+    let master = gf_master(repo);
+    match gf_base_of_ref(repo, base) {
+        Some(base_br) if gf_keeps_base(kind) && base_br != master => {
+            let mut config = repo.config()?.open_level(ConfigLevel::Local)?;
+            config.set_str(&format!("gitflow.branch.{}.base", br_name), &base_br)
+        }
+        _ => Ok(()),
+    }
+}
+
+// Returns whether everything which should have been pushed was pushed.
+fn gf_finish(repo: &Repository, kind: &str, base_br: &str, br: &str, opts: &FinishOpts) -> Result<bool, Error> {
+    let br_name = &gf_branch_name(repo, kind, br);
+    let master = &gf_master(repo);
+    if (kind == "release" || kind == "hotfix") && opts.squash == Some(true) {
+        return Err(Error::from_str(&format!("A {} is merged into {} and can't be squashed", kind, master)));
+    }
+
+    // the branches merged into are checked out here
+    check_not_elsewhere(repo, base_br)?;
+    if kind == "release" || kind == "hotfix" {
+        check_not_elsewhere(repo, master)?;
+    }
+    if let Some(path) = worktree::elsewhere(repo, br_name)? {
+        let question = format!("{} is checked out in the worktree {}, remove the worktree? [y/N]",
+                               br_name, path.display());
+        if !get_input(&question).to_lowercase().starts_with('y') {
+            return Err(Error::from_str(&format!("{} is still checked out in the worktree {}", br_name, path.display())));
+        }
+        worktree::remove(repo, &path)?;
+        say!("Removed the worktree {}", path.display());
+    }
+
+    if opts.rebase {
+        write_finish_state(repo, kind, br, opts)
+            .map_err(|e| Error::from_str(&format!("Can't save the finish state: {}", e)))?;
+        let outcome = gf_rebase(repo, br_name, base_br, &rebase::Options {
+            interactive: false,
+            merges: false,
+            autosquash: gf_autosquash(repo),
+        }).inspect_err(|_| {
+            // --continue is only for a rebase which is still going on
+            if !rebase::in_progress(repo) {
+                remove_finish_state(repo);
+            }
+        })?;
+        if let rebase::Outcome::Stopped(message) = outcome {
+            return Err(rebase_stopped(kind, &message, "finish"));
+        }
+        remove_finish_state(repo);
+    }
+
+    let refname = "refs/heads/".to_owned() + br_name;
+    let brrf = repo.find_reference(&refname)?;
+    let br_commit = repo.reference_to_annotated_commit(&brrf)?;
+    let base_br_ref = repo.find_reference(&("refs/heads/".to_owned() + base_br))?;
+    let analysis = repo.merge_analysis_for_ref(&base_br_ref, &[&br_commit])?;
+    let policy = opts.merge.unwrap_or_else(|| gf_merge_policy(repo, kind));
+    let ff = analysis.0.is_fast_forward() && policy != MergePolicy::NoFf;
+
+    let mut tag = None;
+    if kind == "release" || kind == "hotfix" {
+        let tagname = ask_tag_name(repo, kind)?;
+        let tag_msg = ask_tag_message(repo, kind, &tagname)?;
+        let ctx = MergeContext { kind, name: br, branch: br_name, tag: Some(&tagname) };
+        merge_branch(repo, &ctx, master, br_name, false)?;
+        tag = Some(create_tag(repo, master, &tagname, &tag_msg)?);
+        //merge_tag(&repo, base_br, tag_oid)?;
+        // the back merge brings master, tag and all, into develop
+        let back_ctx = MergeContext { branch: master, ..ctx };
+        merge_branch(repo, &back_ctx, base_br, master, false)?;
+    } else if analysis.0.is_up_to_date() {
+        // nothing to merge, the branch only goes away
+        say!("{} is already in {}", br_name, base_br);
+        checkout_branch(repo, base_br)?;
+    } else {
+        let ctx = MergeContext { kind, name: br, branch: br_name, tag: None };
+        let squash = opts.squash.unwrap_or_else(|| {
+            repo.config()
+                .and_then(|config| config.get_bool(&format!("gitflow.{}.finish.squash", kind)))
+                .unwrap_or(false)
+        });
+        if squash {
+            squash_merge_branch(repo, &ctx, base_br, br_name)?;
+        } else {
+            if policy == MergePolicy::FfOnly && !ff {
+                return Err(Error::from_str(&format!(
+                    "{} can't be fast forwarded onto {}, rebase it first or finish with --rebase",
+                    br_name, base_br)));
+            }
+            if ff {
+                say!("do the fast forward merging");
+            } else {
+                say!("do the normal merging");
+            }
+            merge_branch(repo, &ctx, base_br, br_name, ff)?;
+        }
+    }
+    delete_branch(repo, br_name)?;
+
+    if !opts.push {
+        return Ok(true);
+    }
+
+    let remote = gf_origin(repo);
+    let master_refspec = format!("refs/heads/{0}:refs/heads/{0}", master);
+    let tag_refspec = tag.map(|tag| format!("refs/tags/{0}:refs/tags/{0}", tag));
+    let mut refspecs = Vec::new();
+    if tag_refspec.is_some() {
+        refspecs.push(master_refspec.to_owned());
+    }
+    refspecs.push(format!("refs/heads/{0}:refs/heads/{0}", base_br));
+    refspecs.extend(tag_refspec.iter().cloned());
+    // only delete the remote branch if it was ever published
+    if repo.find_reference(&format!("refs/remotes/{}/{}", remote, br_name)).is_ok() {
+        refspecs.push(format!(":refs/heads/{}", br_name));
+    }
+
+    let mut pushes = vec![(remote, refspecs)];
+    if kind == "release" {
+        let mut mirror_refspecs = vec![master_refspec];
+        mirror_refspecs.extend(tag_refspec);
+        for mirror in gf_release_mirrors(repo) {
+            pushes.push((mirror, mirror_refspecs.clone()));
+        }
+    }
+
+    Ok(gf_push_remotes(repo, &pushes))
+}
+
+fn gf_base_branch(repo: &Repository, kind: &str) -> String {
+    match kind {
+        "support" => gf_master(repo),
+        _ => gf_develop(repo),
+    }
+}
+
+// The base recorded when br_name was started, as long as it still exists,
+// else the one of its kind. master is never finished into this way.
+fn gf_branch_base(repo: &Repository, kind: &str, br_name: &str) -> String {
+    Some(kind).filter(|kind| gf_keeps_base(kind))
+        .and_then(|_| repo.config().ok())
+        .and_then(|config| config.get_string(&format!("gitflow.branch.{}.base", br_name)).ok())
+        .filter(|base| *base != gf_master(repo) && repo.find_branch(base, BranchType::Local).is_ok())
+        .unwrap_or_else(|| gf_base_branch(repo, kind))
+}
+
+fn time_ago(time: Time) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let secs = (now - time.seconds()).max(0);
+    let (count, unit) = match secs {
+        s if s < 60 => (s, "second"),
+        s if s < 60 * 60 => (s / 60, "minute"),
+        s if s < 24 * 60 * 60 => (s / (60 * 60), "hour"),
+        s if s < 30 * 24 * 60 * 60 => (s / (24 * 60 * 60), "day"),
+        s if s < 365 * 24 * 60 * 60 => (s / (30 * 24 * 60 * 60), "month"),
+        s => (s / (365 * 24 * 60 * 60), "year"),
+    };
+    format!("{} {}{} ago", count, unit, if count == 1 { "" } else { "s" })
+}
+
+fn ahead_behind_text(ahead: usize, behind: usize) -> String {
+    match (ahead, behind) {
+        (0, 0) => "in sync".to_owned(),
+        (ahead, 0) => format!("ahead {}", ahead),
+        (0, behind) => format!("behind {}", behind),
+        (ahead, behind) => format!("ahead {}, behind {}", ahead, behind),
+    }
+}
+
+struct Upstream {
+    name: String,
+    // None when the remote branch is gone
+    ahead_behind: Option<(usize, usize)>,
+}
+
+impl Upstream {
+    fn text(&self) -> String {
+        match self.ahead_behind {
+            Some((ahead, behind)) => format!("{}: {}", self.name, ahead_behind_text(ahead, behind)),
+            None => format!("{}: gone", self.name),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "name": self.name,
+            "gone": self.ahead_behind.is_none(),
+            "ahead": self.ahead_behind.map(|(ahead, _)| ahead),
+            "behind": self.ahead_behind.map(|(_, behind)| behind),
+        })
+    }
+}
+
+// None when the branch doesn't track anything.
+fn gf_upstream(repo: &Repository, refname: &str) -> Option<Upstream> {
+    let upstream = repo.branch_upstream_name(refname).ok()?;
+    let upstream = upstream.as_str()?;
+    let local = repo.refname_to_id(refname).ok()?;
+
+    let ahead_behind = match repo.refname_to_id(upstream) {
+        Ok(oid) => Some(repo.graph_ahead_behind(local, oid).ok()?),
+        Err(_) => None,
+    };
+    Some(Upstream { name: upstream.trim_start_matches("refs/remotes/").to_owned(), ahead_behind })
+}
+
+// The local branches of a kind as (name without prefix, branch), sorted.
+fn gf_flow_branches<'a>(repo: &'a Repository, kind: &str) -> Result<Vec<(String, Branch<'a>)>, Error> {
+    let prefix = gf_branch_name(repo, kind, "");
+    let mut branches = Vec::new();
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        if let Some(name) = branch.name()? {
+            if name.starts_with(&prefix) {
+                branches.push((name[prefix.len()..].to_owned(), branch));
+            }
+        }
+    }
+    branches.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Ok(branches)
+}
+
+fn gf_list_branch(repo: &Repository, kind: &str, verbose: bool) -> Result<(), Error> {
+    let branches = gf_flow_branches(repo, kind)?;
+    let width = branches.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+
+    if output::is_human() && branches.is_empty() {
+        say!("No {} branches exist.", kind);
+        return Ok(());
+    }
+
+    let checkouts = worktree::list(repo)?;
+    let here = repo.workdir().and_then(|dir| dir.canonicalize().ok());
+
+    let mut docs = Vec::new();
+    for (name, branch) in &branches {
+        let refname = branch.get().name().unwrap_or("");
+        let worktree = checkouts.iter()
+            .find(|checkout| checkout.branch.as_deref() == Some(refname))
+            .map(|checkout| checkout.path.clone());
+        let elsewhere = worktree.as_ref()
+            .filter(|path| path.canonicalize().ok() != here);
+        let commit = branch.get().peel_to_commit()?;
+        let sha = commit.as_object().short_id()?;
+        let sha = sha.as_str().unwrap_or("");
+        let base = gf_branch_base(repo, kind, refname.trim_start_matches("refs/heads/"));
+        let base_oid = repo.refname_to_id(&("refs/heads/".to_owned() + &base)).ok();
+        let base_ahead_behind = match base_oid {
+            Some(base_oid) => Some(repo.graph_ahead_behind(commit.id(), base_oid)?),
+            None => None,
+        };
+        let upstream = gf_upstream(repo, refname);
+
+        if !output::is_human() {
+            docs.push(json!({
+                "name": name,
+                "branch": refname.trim_start_matches("refs/heads/"),
+                "current": branch.is_head(),
+                "oid": commit.id().to_string(),
+                "subject": commit.summary().unwrap_or(""),
+                "time": commit.time().seconds(),
+                "base": base_ahead_behind.map(|(ahead, behind)| json!({
+                    "name": base,
+                    "ahead": ahead,
+                    "behind": behind,
+                })),
+                "upstream": upstream.as_ref().map(|u| u.to_json()),
+                "worktree": worktree.as_ref().map(|path| path.display().to_string()),
+            }));
+            continue;
+        }
+
+        // like git branch, + for a branch checked out in another worktree
+        let marker = if branch.is_head() { "*" } else if elsewhere.is_some() { "+" } else { " " };
+        if !verbose {
+            say!("{} {}", marker, name);
+            continue;
+        }
+
+        let mut line = format!("{} {:<width$} {}", marker, name, sha, width = width);
+        if let Some((ahead, behind)) = base_ahead_behind {
+            line += &format!(" [{}: {}]", base, ahead_behind_text(ahead, behind));
+        }
+        if let Some(upstream) = &upstream {
+            line += &format!(" [{}]", upstream.text());
+        }
+        if let Some(path) = elsewhere {
+            line += &format!(" [worktree: {}]", path.display());
+        }
+        line += &format!(" {} ({})", commit.summary().unwrap_or(""), time_ago(commit.time()));
+        say!("{}", line);
+    }
+
+    output::emit(&json!({ "kind": kind, "branches": docs }));
+
+    Ok(())
+}
+
+// Split a branch name into its flow kind and the name without prefix.
+fn gf_kind_of(repo: &Repository, br_name: &str) -> Option<(&'static str, String)> {
+    FLOW_KINDS.iter()
+        .map(|kind| (*kind, gf_branch_name(repo, kind, "")))
+        .filter(|(_, prefix)| !prefix.is_empty() && br_name.starts_with(prefix.as_str()))
+        .max_by_key(|(_, prefix)| prefix.len())
+        .map(|(kind, prefix)| (kind, br_name[prefix.len()..].to_owned()))
+}
+
+// The newest version tag, only among those in the history of within when
+// that is given.
+fn gf_latest_version_tag(repo: &Repository, within: Option<Oid>) -> Option<String> {
+    let versiontag = gf_config_or(repo, "gitflow.prefix.versiontag", "");
+    let names = repo.tag_names(Some(&(versiontag + "*"))).ok()?;
+    names.iter()
+        .flatten()
+        .filter_map(|name| {
+            let commit = repo.revparse_single(&("refs/tags/".to_owned() + name)).ok()?
+                .peel_to_commit().ok()?;
+            if let Some(within) = within {
+                if commit.id() != within && !repo.graph_descendant_of(within, commit.id()).ok()? {
+                    return None;
+                }
+            }
+            Some((commit.time().seconds(), name.to_owned()))
+        })
+        .max()
+        .map(|(_, name)| name)
+}
+
+fn repo_state_text(state: RepositoryState) -> &'static str {
+    match state {
+        RepositoryState::Clean => "clean",
+        RepositoryState::Merge => "merge in progress",
+        RepositoryState::Revert | RepositoryState::RevertSequence => "revert in progress",
+        RepositoryState::CherryPick | RepositoryState::CherryPickSequence => "cherry-pick in progress",
+        RepositoryState::Bisect => "bisect in progress",
+        RepositoryState::Rebase | RepositoryState::RebaseInteractive
+            | RepositoryState::RebaseMerge => "rebase in progress",
+        RepositoryState::ApplyMailbox | RepositoryState::ApplyMailboxOrRebase => "am in progress",
+    }
+}
+
+fn gf_status(repo: &Repository) -> Result<(), Error> {
+    let config = repo.config()?;
+    let master = gf_master(repo);
+    let develop = gf_develop(repo);
+    let initialized = config.get_string("gitflow.branch.master").is_ok()
+        && config.get_string("gitflow.branch.develop").is_ok();
+
+    let head = repo.head().ok();
+    let br_name = head.as_ref().filter(|h| h.is_branch()).and_then(|h| h.shorthand());
+    let detached = head.as_ref().filter(|h| !h.is_branch()).and_then(|h| h.target());
+    let refname = br_name.map(|br| "refs/heads/".to_owned() + br);
+    let flow = br_name.and_then(|br| gf_kind_of(repo, br));
+    let base = flow.as_ref().zip(br_name).map(|((kind, _), br)| gf_branch_base(repo, kind, br));
+    let base_ahead_behind = match (&refname, &base) {
+        (Some(refname), Some(base)) => {
+            match repo.refname_to_id(&("refs/heads/".to_owned() + base)) {
+                Ok(base_oid) => Some(repo.graph_ahead_behind(repo.refname_to_id(refname)?, base_oid)?),
+                Err(_) => None,
+            }
+        }
+        _ => None,
+    };
+    let upstream = refname.as_ref().and_then(|refname| gf_upstream(repo, refname));
+    let state = match (rebase::branch(repo), read_finish_state(repo)) {
+        (Some(br), Some(_)) => format!("finish --rebase of {} stopped on conflicts", br),
+        (Some(br), None) => format!("rebase of {} stopped on conflicts", br),
+        _ => repo_state_text(repo.state()).to_owned(),
+    };
+    let pending = read_pending_push(repo);
+    let mut counts = Vec::new();
+    for kind in &FLOW_KINDS {
+        counts.push((*kind, gf_flow_branches(repo, kind)?.len()));
+    }
+    let latest_tag = gf_latest_version_tag(repo, None);
+
+    if !output::is_human() {
+        let open: serde_json::Map<String, serde_json::Value> = counts.iter()
+            .map(|(kind, count)| (kind.to_string(), json!(count)))
+            .collect();
+        output::emit(&json!({
+            "initialized": initialized,
+            "master": master,
+            "develop": develop,
+            "branch": br_name,
+            "detached": detached.map(|oid| oid.to_string()),
+            "kind": flow.as_ref().map(|(kind, _)| kind),
+            "name": flow.as_ref().map(|(_, name)| name),
+            "base": base.as_ref().map(|base| json!({
+                "name": base,
+                "ahead": base_ahead_behind.map(|(ahead, _)| ahead),
+                "behind": base_ahead_behind.map(|(_, behind)| behind),
+            })),
+            "upstream": upstream.as_ref().map(|u| u.to_json()),
+            "state": state,
+            "pending_pushes": pending.len(),
+            "open": open,
+            "latest_tag": latest_tag,
+        }));
+        return Ok(());
+    }
+
+    if initialized {
+        say!("Git flow: initialized (production: {}, development: {})", master, develop);
+    } else {
+        say!("Git flow: not initialized, run `git flow init`");
+    }
+    match (br_name, &flow) {
+        (Some(br_name), Some((kind, name))) => say!("Current branch: {} ({} {})", br_name, kind, name),
+        (Some(br_name), None) => say!("Current branch: {}", br_name),
+        (None, _) => match detached {
+            Some(oid) => say!("Current branch: HEAD detached at {}", oid),
+            None => say!("Current branch: none"),
+        },
+    }
+    if let Some(base) = &base {
+        match base_ahead_behind {
+            Some((ahead, behind)) => say!("Base branch: {} ({})", base, ahead_behind_text(ahead, behind)),
+            None => say!("Base branch: {} (missing)", base),
+        }
+    }
+    if br_name.is_some() {
+        match &upstream {
+            Some(upstream) => say!("Upstream: {}", upstream.text()),
+            None => say!("Upstream: none"),
+        }
+    }
+    say!("State: {}", state);
+    if !pending.is_empty() {
+        say!("Pending pushes: {} refs, run `git flow push-pending`", pending.len());
+    }
+    let counts: Vec<String> = counts.iter().map(|(kind, count)| format!("{} {}", kind, count)).collect();
+    say!("Open branches: {}", counts.join(", "));
+    say!("Latest version tag: {}", latest_tag.unwrap_or_else(|| "none".to_owned()));
+
+    Ok(())
+}
+
+struct CleanupOpts<'a> {
+    kinds: Vec<&'a str>,
+    days: Option<i64>,
+    remote: bool,
+    yes: bool,
+    dry_run: bool,
+}
+
+struct CleanupCandidate {
+    kind: &'static str,
+    branch: String,
+    oid: Oid,
+    time: Time,
+    reasons: Vec<String>,
+    // the branch on the flow remote, when there is one to delete
+    remote_branch: Option<String>,
+}
+
+fn gf_cleanup_candidates(repo: &Repository, opts: &CleanupOpts) -> Result<Vec<CleanupCandidate>, Error> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let remote = gf_origin(repo);
+    let master = gf_master(repo);
+    let master_oid = repo.refname_to_id(&("refs/heads/".to_owned() + &master)).ok();
+    let mut candidates = Vec::new();
+
+    for kind in FLOW_KINDS.iter().filter(|kind| opts.kinds.contains(kind)) {
+        for (_, branch) in gf_flow_branches(repo, kind)? {
+            if branch.is_head() {
+                continue;
+            }
+            let refname = branch.get().name().unwrap_or("").to_owned();
+            let br_name = refname.trim_start_matches("refs/heads/").to_owned();
+            let base = gf_branch_base(repo, kind, &br_name);
+            let base_oid = repo.refname_to_id(&("refs/heads/".to_owned() + &base)).ok();
+            let commit = branch.get().peel_to_commit()?;
+
+            // a branch without commits of its own is new rather than merged:
+            // its tip is where it was created, or without a reflog where the
+            // base still is
+            let created_at = repo.reflog(&refname).ok()
+                .and_then(|reflog| reflog.iter().next_back().map(|entry| entry.id_new()));
+            let own_commits = match created_at {
+                Some(oid) => oid != commit.id(),
+                None => base_oid != Some(commit.id()),
+            };
+            let merged_into = |target: Option<Oid>| match target {
+                Some(target) if own_commits => Ok(target == commit.id() || repo.graph_descendant_of(target, commit.id())?),
+                _ => Ok::<bool, Error>(false),
+            };
+            let mut reasons = Vec::new();
+            if merged_into(base_oid)? {
+                reasons.push(format!("merged into {}", base));
+            } else if base != master && merged_into(master_oid)? {
+                reasons.push(format!("merged into {}", master));
+            }
+            if let Some(days) = opts.days {
+                if now - commit.time().seconds() > days * 24 * 60 * 60 {
+                    reasons.push(format!("idle for {} days", (now - commit.time().seconds()) / (24 * 60 * 60)));
+                }
+            }
+            if let Some(Upstream { name, ahead_behind: None }) = gf_upstream(repo, &refname) {
+                reasons.push(format!("upstream {} is gone", name));
+            }
+            if reasons.is_empty() {
+                continue;
+            }
+
+            let remote_ref = format!("refs/remotes/{}/{}", remote, br_name);
+            candidates.push(CleanupCandidate {
+                kind,
+                remote_branch: repo.find_reference(&remote_ref).ok().map(|_| br_name.clone()),
+                branch: br_name,
+                oid: commit.id(),
+                time: commit.time(),
+                reasons,
+            });
+        }
+    }
+
+    Ok(candidates)
+}
+
+// Delete flow branches which are merged, idle or have lost their upstream.
+fn gf_cleanup(repo: &Repository, opts: &CleanupOpts) -> Result<bool, Error> {
+    let candidates = gf_cleanup_candidates(repo, opts)?;
+    let remote = gf_origin(repo);
+
+    if candidates.is_empty() {
+        say!("No flow branches to clean up.");
+    } else {
+        let width = candidates.iter().map(|c| c.branch.len()).max().unwrap_or(0).max("BRANCH".len());
+        let ages: Vec<String> = candidates.iter().map(|c| time_ago(c.time)).collect();
+        let age_width = ages.iter().map(|age| age.len()).max().unwrap_or(0).max("LAST COMMIT".len());
+        say!("{:<width$}  {:<age_width$}  REASON", "BRANCH", "LAST COMMIT", width = width, age_width = age_width);
+        for (candidate, age) in candidates.iter().zip(&ages) {
+            say!("{:<width$}  {:<age_width$}  {}", candidate.branch, age, candidate.reasons.join(", "),
+                width = width, age_width = age_width);
+        }
+    }
+
+    let mut deleted = Vec::new();
+    let mut remote_deleted = Vec::new();
+    let mut ok = true;
+    if !candidates.is_empty() && !opts.dry_run {
+        let question = if opts.remote {
+            format!("Delete these {} branches here and on {}? [y/N]", candidates.len(), remote)
+        } else {
+            format!("Delete these {} branches? [y/N]", candidates.len())
+        };
+        if opts.yes || get_input(&question).to_lowercase().starts_with('y') {
+            for candidate in &candidates {
+                let short_id = repo.find_object(candidate.oid, None)?.short_id()?;
+                match delete_branch(repo, &candidate.branch) {
+                    Ok(()) => {
+                        say!("Deleted branch {} (was {}).", candidate.branch, short_id.as_str().unwrap_or(""));
+                        deleted.push(candidate.branch.clone());
+                    }
+                    Err(e) => {
+                        say!("Deleting {} failed: {}", candidate.branch, e.message());
+                        ok = false;
+                    }
+                }
+            }
+
+            // only branches deleted here go on the remote, and only when the
+            // remote has nothing the deleted tip didn't have
+            let mut refspecs = Vec::new();
+            for candidate in candidates.iter().filter(|c| opts.remote && deleted.contains(&c.branch)) {
+                let br = match &candidate.remote_branch {
+                    Some(br) => br,
+                    None => continue,
+                };
+                let remote_oid = repo.refname_to_id(&format!("refs/remotes/{}/{}", remote, br))?;
+                if remote_oid == candidate.oid || repo.graph_descendant_of(candidate.oid, remote_oid)? {
+                    refspecs.push(format!(":refs/heads/{}", br));
+                } else {
+                    say!("Not deleting {} on {}, it has commits which {} didn't have", br, remote, candidate.branch);
+                    ok = false;
+                }
+            }
+            if !refspecs.is_empty() {
+                for st in gf_push_statuses(repo, &remote, &refspecs) {
+                    match st.error {
+                        None => remote_deleted.push(st.refspec.trim_start_matches(":refs/heads/").to_owned()),
+                        Some(_) => ok = false,
+                    }
+                }
+            }
+        } else {
+            say!("Nothing deleted.");
+        }
+    }
+
+    output::emit(&json!({
+        "candidates": candidates.iter().map(|c| json!({
+            "kind": c.kind,
+            "branch": c.branch,
+            "oid": c.oid.to_string(),
+            "time": c.time.seconds(),
+            "reasons": c.reasons,
+            "remote_branch": c.remote_branch,
+        })).collect::<Vec<_>>(),
+        "deleted": deleted,
+        "remote_deleted": remote_deleted,
+    }));
+
+    Ok(ok)
+}
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                            "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+// The date the way git log shows it, in the commit's own timezone.
+fn format_time(time: Time) -> String {
+    let offset = time.offset_minutes() as i64;
+    let secs = time.seconds() + offset * 60;
+    let days = secs.div_euclid(24 * 60 * 60);
+    let secs_of_day = secs.rem_euclid(24 * 60 * 60);
+
+    // civil date from days since 1970-01-01
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{} {} {} {:02}:{:02}:{:02} {} {}{:02}{:02}",
+        WEEKDAYS[(days + 4).rem_euclid(7) as usize],
+        MONTHS[(month - 1) as usize],
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        year,
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 60,
+        offset.abs() % 60)
+}
+
+struct LogOpts<'a> {
+    oneline: bool,
+    stat: bool,
+    reverse: bool,
+    authors: Vec<&'a str>,
+}
+
+fn io_error(e: io::Error) -> Error {
+    Error::from_str(&e.to_string())
+}
+
+fn commit_stat(repo: &Repository, commit: &Commit) -> Result<String, Error> {
+    let parent_tree = match commit.parent(0) {
+        Ok(parent) => Some(parent.tree()?),
+        Err(_) => None,
+    };
+    let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+    let stats = diff.stats()?.to_buf(DiffStatsFormat::FULL, 80)?;
+    Ok(stats.as_str().unwrap_or("").to_owned())
+}
+
+// The commits on br_name which are not on base_br yet.
+fn gf_log(repo: &Repository, br_name: &str, base_br: &str, opts: &LogOpts) -> Result<(), Error> {
+    let colors = color::palette(repo, "diff");
+    let mut out = pager::Pager::start(repo);
+    let mut revwalk = repo.revwalk()?;
+    let mut sorting = Sort::TOPOLOGICAL | Sort::TIME;
+    if opts.reverse {
+        sorting |= Sort::REVERSE;
+    }
+    revwalk.set_sorting(sorting)?;
+    revwalk.push_ref(&("refs/heads/".to_owned() + br_name))?;
+    let base_ref = "refs/heads/".to_owned() + base_br;
+    if repo.find_reference(&base_ref).is_ok() {
+        revwalk.hide_ref(&base_ref)?;
+    }
+
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        let author = commit.author();
+        let author = format!("{} <{}>", author.name().unwrap_or(""), author.email().unwrap_or(""));
+        if !opts.authors.is_empty() && !opts.authors.iter()
+            .any(|pattern| author.to_lowercase().contains(&pattern.to_lowercase())) {
+            continue;
+        }
+
+        if opts.oneline {
+            let short_id = commit.as_object().short_id()?;
+            writeln!(out, "{}{}{} {}", colors.commit, short_id.as_str().unwrap_or(""), colors.reset,
+                commit.summary().unwrap_or("")).map_err(io_error)?;
+        } else {
+            writeln!(out, "{}commit {}{}", colors.commit, commit.id(), colors.reset).map_err(io_error)?;
+            if commit.parent_count() > 1 {
+                let parents: Vec<String> = commit.parent_ids().map(|id| id.to_string()[..7].to_owned()).collect();
+                writeln!(out, "Merge: {}", parents.join(" ")).map_err(io_error)?;
+            }
+            writeln!(out, "Author: {}", author).map_err(io_error)?;
+            writeln!(out, "Date:   {}", format_time(commit.author().when())).map_err(io_error)?;
+            writeln!(out).map_err(io_error)?;
+            for line in commit.message().unwrap_or("").trim_end().lines() {
+                writeln!(out, "    {}", line).map_err(io_error)?;
+            }
+            writeln!(out).map_err(io_error)?;
+        }
+        if opts.stat {
+            write!(out, "{}", commit_stat(repo, &commit)?).map_err(io_error)?;
+            if !opts.oneline {
+                writeln!(out).map_err(io_error)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DiffMode {
+    Patch,
+    Stat,
+    NameOnly,
+    NameStatus,
+}
+
+struct DiffOpts<'a> {
+    mode: DiffMode,
+    paths: Vec<&'a str>,
+}
+
+fn delta_status(status: Delta) -> char {
+    match status {
+        Delta::Added => 'A',
+        Delta::Deleted => 'D',
+        Delta::Modified => 'M',
+        Delta::Renamed => 'R',
+        Delta::Copied => 'C',
+        Delta::Typechange => 'T',
+        Delta::Unreadable => 'X',
+        _ => 'U',
+    }
+}
+
+// What new has changed since it forked from old, like `git diff old...new`.
+fn branch_diff<'r>(repo: &'r Repository, old: &str, new: &str, paths: &[&str]) -> Result<Diff<'r>, Error> {
+    let old_commit = repo.revparse_single(old)?.peel_to_commit()?;
+    let new_commit = repo.revparse_single(new)?.peel_to_commit()?;
+    let base = repo.merge_base(old_commit.id(), new_commit.id())?;
+    let base_tree = repo.find_commit(base)?.tree()?;
+
+    let mut diff_opts = DiffOptions::new();
+    for path in paths {
+        diff_opts.pathspec(path);
+    }
+    let mut diff = repo.diff_tree_to_tree(Some(&base_tree), Some(&new_commit.tree()?), Some(&mut diff_opts))?;
+    diff.find_similar(Some(DiffFindOptions::new().renames(true).copies(true)))?;
+    Ok(diff)
+}
+
+fn gf_diff_branches(repo: &Repository, old: &str, new: &str, opts: &DiffOpts) -> Result<(), Error> {
+    let diff = branch_diff(repo, old, new, &opts.paths)?;
+
+    let colors = color::palette(repo, "diff");
+    let mut out = pager::Pager::start(repo);
+    match opts.mode {
+        DiffMode::Stat => {
+            let stats = diff.stats()?.to_buf(DiffStatsFormat::FULL, 80)?;
+            write!(out, "{}", stats.as_str().unwrap_or("")).map_err(io_error)?;
+        }
+        DiffMode::NameOnly | DiffMode::NameStatus => {
+            for delta in diff.deltas() {
+                let path = |file: DiffFile| file.path().map(|p| p.display().to_string()).unwrap_or_default();
+                let status = delta.status();
+                if opts.mode == DiffMode::NameOnly {
+                    writeln!(out, "{}", path(delta.new_file())).map_err(io_error)?;
+                } else if status == Delta::Renamed || status == Delta::Copied {
+                    writeln!(out, "{}\t{}\t{}", delta_status(status), path(delta.old_file()), path(delta.new_file())).map_err(io_error)?;
+                } else {
+                    writeln!(out, "{}\t{}", delta_status(status), path(delta.new_file())).map_err(io_error)?;
+                }
+            }
+        }
+        DiffMode::Patch => {
+            let mut failed = None;
+            let context = String::new();
+            diff.print(DiffFormat::Patch, |delta, _hunk, line| {
+                let color = match line.origin() {
+                    '+' | '>' => &colors.new,
+                    '-' | '<' => &colors.old,
+                    'F' => &colors.meta,
+                    'H' => &colors.frag,
+                    _ => &context,
+                };
+                let prefix = match line.origin() {
+                    '+' | '-' | ' ' => line.origin().to_string(),
+                    _ => String::new(),
+                };
+                // libgit2 sends a "Binary files ... differ" line for binary
+                // deltas, anything else which is not UTF-8 is shown lossily.
+                let content = if line.origin() == 'B' && line.content().is_empty() {
+                    let path = |file: DiffFile| file.path().map(|p| p.display().to_string()).unwrap_or_default();
+                    format!("Binary files a/{} and b/{} differ\n", path(delta.old_file()), path(delta.new_file()))
+                } else {
+                    String::from_utf8_lossy(line.content()).into_owned()
+                };
+                // color each line on its own so that a pager never sees
+                // a color running on past the end of a line
+                let result = if color.is_empty() {
+                    write!(out, "{}{}", prefix, content)
+                } else {
+                    let text = prefix + &content;
+                    let text = text.trim_end_matches('\n');
+                    let lines: Vec<String> = text.split('\n')
+                        .map(|l| format!("{}{}{}", color, l, colors.reset))
+                        .collect();
+                    writeln!(out, "{}", lines.join("\n"))
+                };
+                match result {
+                    Ok(()) => true,
+                    Err(e) => {
+                        failed = Some(e);
+                        false
+                    }
+                }
+            }).or_else(|e| if failed.is_some() { Ok(()) } else { Err(e) })?;
+            if let Some(e) = failed {
+                return Err(io_error(e));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn gf_publish(repo: &Repository, kind: &str, br_name: &str) -> bool {
+    let mut pushes = vec![(gf_origin(repo), vec![format!("refs/heads/{0}:refs/heads/{0}", br_name)])];
+
+    // the mirrors only get what is released: the production branch and
+    // the latest version tag on it, never the release branch
+    if kind == "release" {
+        let master = gf_master(repo);
+        let mut refspecs = vec![format!("refs/heads/{0}:refs/heads/{0}", master)];
+        let master_oid = repo.refname_to_id(&("refs/heads/".to_owned() + &master)).ok();
+        if let Some(tag) = master_oid.and_then(|oid| gf_latest_version_tag(repo, Some(oid))) {
+            refspecs.push(format!("refs/tags/{0}:refs/tags/{0}", tag));
+        }
+        for mirror in gf_release_mirrors(repo) {
+            pushes.push((mirror, refspecs.clone()));
+        }
+    }
+
+    gf_push_remotes(repo, &pushes)
+}
+
+fn gf_track(repo: &Repository, br_name: &str) -> Result<(), Error> {
+    let remote_name = gf_origin(repo);
+    let mut remote = repo.find_remote(&remote_name)?;
+
+    let fetch_commit = do_fetch(repo, &[br_name], &mut remote)?;
+    do_merge(repo, br_name, fetch_commit)
+}
+
+fn start_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("slugify")
+            .long("slugify")
+            .help("Turn free text like \"Fix the login page\" into a valid name"),
+        worktree_arg(),
+    ]
+}
+
+fn worktree_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("worktree")
+        .long("worktree")
+        .takes_value(true)
+        .min_values(0)
+        .require_equals(true)
+        .value_name("path")
+        .help("Check the branch out in a worktree of its own, under gitflow.path.worktrees \
+               unless a path is given")
+}
+
+// Where --worktree asks for the branch to be checked out, None without it.
+fn gf_worktree_path(repo: &Repository, matches: &ArgMatches, br_name: &str) -> Result<Option<PathBuf>, Error> {
+    if !matches.is_present("worktree") {
+        return Ok(None);
+    }
+    match matches.value_of("worktree") {
+        Some(path) => Ok(Some(PathBuf::from(path))),
+        None => worktree::default_path(repo, br_name).map(Some),
+    }
+}
+
+fn finish_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("push")
+            .long("push")
+            .help("Push the merged branches and tag, and delete the remote branch. \
+                   Releases also go to gitflow.release.remotes"),
+        Arg::with_name("squash")
+            .long("squash")
+            .help("Put all the changes into a single commit instead of merging, \
+                   the default with gitflow.<kind>.finish.squash"),
+        Arg::with_name("no-squash")
+            .long("no-squash")
+            .conflicts_with("squash")
+            .help("Merge even when gitflow.<kind>.finish.squash is set"),
+        Arg::with_name("rebase")
+            .long("rebase")
+            .help("Rebase onto the base branch first, to keep the history linear"),
+        Arg::with_name("no-ff")
+            .long("no-ff")
+            .help("Create a merge commit even when a fast forward is possible, \
+                   the default with gitflow.<kind>.finish.no-ff or merge.ff=false"),
+        Arg::with_name("ff")
+            .long("ff")
+            .conflicts_with_all(&["no-ff", "ff-only"])
+            .help("Fast forward when possible, whatever the configuration says"),
+        Arg::with_name("ff-only")
+            .long("ff-only")
+            .conflicts_with("no-ff")
+            .help("Fail unless the base branch can be fast forwarded, \
+                   the default with merge.ff=only"),
+        Arg::with_name("continue")
+            .long("continue")
+            .help("Go on with a finish --rebase which stopped on conflicts"),
+    ]
+}
+
+fn rebase_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("interactive")
+            .short("i")
+            .long("interactive")
+            .help("Edit the list of commits to pick, reword, squash, fixup or drop first"),
+        Arg::with_name("rebase-merges")
+            .short("r")
+            .long("rebase-merges")
+            .help("Recreate the merges instead of leaving them out"),
+        Arg::with_name("autosquash")
+            .long("autosquash")
+            .help("Fold fixup!, squash! and amend! commits into the commits they name, \
+                   the default with rebase.autoSquash"),
+        Arg::with_name("no-autosquash")
+            .long("no-autosquash")
+            .conflicts_with("autosquash")
+            .help("Keep fixup!, squash! and amend! commits even when rebase.autoSquash is set"),
+        Arg::with_name("continue")
+            .long("continue")
+            .help("Go on after the conflicts were resolved and added"),
+        Arg::with_name("skip")
+            .long("skip")
+            .conflicts_with("continue")
+            .help("Leave out the commit which stopped on conflicts and go on"),
+        Arg::with_name("abort")
+            .long("abort")
+            .conflicts_with_all(&["continue", "skip"])
+            .help("Give up and put the branch back where it was"),
+    ]
+}
+
+fn list_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .help("Show the tip commit, how far each branch is from its base and its upstream"),
+    ]
+}
+
+fn log_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("oneline")
+            .long("oneline")
+            .help("Show each commit on a single line"),
+        Arg::with_name("stat")
+            .long("stat")
+            .help("Show the files changed by each commit"),
+        Arg::with_name("reverse")
+            .long("reverse")
+            .help("Show the oldest commit first"),
+        Arg::with_name("author")
+            .long("author")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Only show commits whose author name or email contains this"),
+    ]
+}
+
+fn diff_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("stat")
+            .long("stat")
+            .help("Show a diffstat instead of the patch"),
+        Arg::with_name("name-only")
+            .long("name-only")
+            .conflicts_with_all(&["stat", "name-status"])
+            .help("Show only the names of the changed files"),
+        Arg::with_name("name-status")
+            .long("name-status")
+            .conflicts_with("stat")
+            .help("Show the names and the kind of change of the changed files"),
+        Arg::with_name("paths")
+            .multiple(true)
+            .last(true)
+            .help("Only show the changes under these paths, given after --"),
+    ]
+}
+
+// The commands which have pre-flow and post-flow hooks.
+const HOOKED_COMMANDS: [&str; 7] = ["start", "finish", "publish", "track", "rebase", "checkout", "delete"];
+
+// The repository like git finds it: GIT_DIR, or the one the current
+// directory is in, with the work tree moved by GIT_WORK_TREE.
+fn gf_open_repo() -> Result<Repository, Error> {
+    // libgit2 refuses GIT_WORK_TREE, so it is kept away from the lookup
+    // and applied afterwards, hooks and editors still see it
+    let work_tree = env::var_os("GIT_WORK_TREE");
+    env::remove_var("GIT_WORK_TREE");
+    let repo = Repository::open_from_env();
+    if let Some(work_tree) = &work_tree {
+        env::set_var("GIT_WORK_TREE", work_tree);
+    }
+    let repo = repo.map_err(|_| Error::from_str("Not a git repository (or any of the parent directories)"))?;
+    if let Some(work_tree) = work_tree {
+        let work_tree = env::current_dir()
+            .map(|dir| dir.join(&work_tree))
+            .unwrap_or_else(|_| PathBuf::from(&work_tree));
+        repo.set_workdir(&work_tree, false)?;
+    }
+    Ok(repo)
+}
+
+fn gf_run_flow(kind: &str, matches: &ArgMatches) -> i32 {
+    let repo = match gf_open_repo() {
+        Ok(repo) => repo,
+        Err(e) => {
+            say!("{}", e.message());
+            return 1;
+        }
+    };
+    let (command, sub) = matches.subcommand();
+    let sub = match sub {
+        Some(sub) if HOOKED_COMMANDS.contains(&command) => sub,
+        _ => return gf_run_flow_command(&repo, kind, matches, None).unwrap_or(1),
+    };
+
+    // name, origin and branch, with the current branch when no name is given
+    let mut name = match sub.value_of(kind.to_owned() + "_name") {
+        Some(name) => name.to_owned(),
+        None => repo.head().ok().as_ref()
+            .and_then(|head| head.shorthand())
+            .and_then(|br| gf_kind_of(&repo, br))
+            .filter(|(k, _)| *k == kind)
+            .map(|(_, name)| name)
+            .unwrap_or_default(),
+    };
+    if command == "start" && sub.is_present("slugify") {
+        name = slugify(&name);
+    }
+    // releases and hotfixes are named after their version
+    if command == "start" {
+        let what = if kind == "release" || kind == "hotfix" { "version" } else { "name" };
+        name = match hooks::filter(&repo, &format!("filter-flow-{}-start-{}", kind, what), &name, &[]) {
+            Ok(name) if !name.is_empty() => name,
+            Ok(_) => {
+                say!("Aborted start {} {}: the start filter gave an empty {}", kind, name, what);
+                return 1;
+            }
+            Err(e) => {
+                say!("Aborted start {} {}: {}", kind, name, e.message());
+                return 1;
+            }
+        };
+    }
+    // hooks only hear of names which are going to be used
+    if command == "start" {
+        if let Err(e) = gf_check_name(&repo, kind, &name) {
+            say!("Start {} {} failed: {}", kind, name, e.message());
+            return 1;
+        }
+    }
+    let branch = gf_branch_name(&repo, kind, &name);
+    let origin = gf_origin(&repo);
+    let args = [name.as_str(), origin.as_str(), branch.as_str()];
+
+    if let Err(e) = hooks::run(&repo, &format!("pre-flow-{}-{}", kind, command), &args) {
+        say!("Aborted {} {} {}: {}", command, kind, name, e.message());
+        return 1;
+    }
+    match gf_run_flow_command(&repo, kind, matches, Some(&name)) {
+        Some(code) => {
+            if let Err(e) = hooks::run(&repo, &format!("post-flow-{}-{}", kind, command), &args) {
+                say!("Warning: {}", e.message());
+            }
+            code
+        }
+        None => 1,
+    }
+}
+
+// `start_name` is the name to start, after the start filter ran. Returns
+// the exit code once the command did its work, None when it failed.
+fn gf_run_flow_command(repo: &Repository, kind: &str, matches: &ArgMatches, start_name: Option<&str>) -> Option<i32> {
+    let name_arg = &(kind.to_owned() + "_name");
+    let before = output::ref_snapshot(repo);
+    // What start, finish and publish did, for --format json and --porcelain
+    let report = |command: &str, name: &str, error: Option<&str>| {
+        let after = output::ref_snapshot(repo);
+        output::emit(&output::operation_result(command, kind, name, error, &before, &after));
+    };
+
+    match matches.subcommand() {
+        ("start", Some(match_sub1)) => {
+            let br = start_name.unwrap_or_else(|| match_sub1.value_of(name_arg).unwrap());
+            let base = gf_base_branch(repo, kind);
+            let base = match_sub1.value_of("base_branch").unwrap_or(&base);
+            let result = gf_check_name(repo, kind, br)
+                .and_then(|()| gf_worktree_path(repo, match_sub1, &gf_branch_name(repo, kind, br)))
+                .and_then(|path| gf_start(repo, kind, base, br, path.as_deref()));
+            report("start", br, result.as_ref().err().map(|e| e.message()));
+            match result {
+                Ok(()) => say!("Start {} {} successfully", kind, br),
+                Err(e) => {
+                    say!("Start {} {} failed: {}", kind, br, e.message());
+                    return None;
+                },
+            }
+        }
+        ("finish", Some(match_sub1)) => {
+            let (br, result) = if match_sub1.is_present("continue") {
+                match gf_finish_continue(repo, kind) {
+                    Ok((br, pushed)) => (br, Ok(pushed)),
+                    Err(e) => (read_finish_state(repo).map(|(_, br, _)| br).unwrap_or_default(), Err(e)),
+                }
+            } else {
+                let br = match_sub1.value_of(name_arg).unwrap();
+                let opts = FinishOpts {
+                    push: match_sub1.is_present("push"),
+                    squash: if match_sub1.is_present("squash") {
+                        Some(true)
+                    } else if match_sub1.is_present("no-squash") {
+                        Some(false)
+                    } else {
+                        None
+                    },
+                    rebase: match_sub1.is_present("rebase"),
+                    merge: if match_sub1.is_present("no-ff") {
+                        Some(MergePolicy::NoFf)
+                    } else if match_sub1.is_present("ff-only") {
+                        Some(MergePolicy::FfOnly)
+                    } else if match_sub1.is_present("ff") {
+                        Some(MergePolicy::Ff)
+                    } else {
+                        None
+                    },
+                };
+                (br.to_owned(), gf_finish(repo, kind, &gf_branch_base(repo, kind, &gf_branch_name(repo, kind, br)), br, &opts))
+            };
+            let br = &br;
+            report("finish", br, result.as_ref().err().map(|e| e.message()));
+            match result {
+                Ok(pushed) => {
+                    say!("Finish {} {} successfully", kind, br);
+                    // finished all the same, only the push is left to retry
+                    if !pushed {
+                        return Some(1);
+                    }
+                },
+                Err(e) => {
+                    say!("Finish {} {} failed: {}", kind, br, e.message());
+                    return None;
+                },
+            }
+        }
+        ("list", Some(match_sub1)) => {
+            if let Err(e) = gf_list_branch(repo, kind, match_sub1.is_present("verbose")) {
+                say!("List {} branches failed: {}", kind, e.message());
+                return None;
+            }
+        }
+        ("publish", Some(match_sub1)) => {
+            let br_name = match match_sub1.value_of(name_arg) {
+                Some(br) => gf_branch_name(repo, kind, br),
+                None => repo.head().ok()
+                    .and_then(|head| head.shorthand().map(|s| s.to_owned()))
+                    .expect("No branch to publish"),
+            };
+            let pushed = gf_publish(repo, kind, &br_name);
+            report("publish", &br_name, if pushed { None } else { Some("push failed") });
+            if !pushed {
+                return None;
+            }
+        }
+        ("track", Some(match_sub1)) => {
+            let br = match_sub1.value_of(name_arg)
+                .expect("No branch name input");
+            let br_name = &gf_branch_name(repo, kind, br);
+            if let Err(e) = gf_track(repo, br_name) {
+                say!("Track {} failed: {}", br_name, e.message());
+                return None;
+            }
+        }
+        ("log", Some(match_sub1)) => {
+            let br_name = match match_sub1.value_of(name_arg) {
+                Some(br) => gf_branch_name(repo, kind, br),
+                None => match repo.head().ok().as_ref().and_then(|h| h.shorthand())
+                    .filter(|br| gf_kind_of(repo, br).map(|(k, _)| k) == Some(kind)) {
+                    Some(br) => br.to_owned(),
+                    None => {
+                        say!("Not on a {} branch, give the {} name", kind, kind);
+                        return None;
+                    }
+                },
+            };
+            let opts = LogOpts {
+                oneline: match_sub1.is_present("oneline"),
+                stat: match_sub1.is_present("stat"),
+                reverse: match_sub1.is_present("reverse"),
+                authors: match_sub1.values_of("author").map(|v| v.collect()).unwrap_or_default(),
+            };
+            if let Err(e) = gf_log(repo, &br_name, &gf_branch_base(repo, kind, &br_name), &opts) {
+                say!("Log {} failed: {}", br_name, e.message());
+                return None;
+            }
+        }
+        ("diff", Some(match_sub1)) => {
+            let new = match match_sub1.value_of(name_arg) {
+                Some(br) => gf_branch_name(repo, kind, br),
+                None => "HEAD".to_owned(),
+            };
+            let mode = if match_sub1.is_present("stat") {
+                DiffMode::Stat
+            } else if match_sub1.is_present("name-only") {
+                DiffMode::NameOnly
+            } else if match_sub1.is_present("name-status") {
+                DiffMode::NameStatus
+            } else {
+                DiffMode::Patch
+            };
+            let opts = DiffOpts {
+                mode,
+                paths: match_sub1.values_of("paths").map(|v| v.collect()).unwrap_or_default(),
+            };
+            let base = if new == "HEAD" {
+                let head = repo.head().ok();
+                gf_branch_base(repo, kind, head.as_ref().and_then(|h| h.shorthand()).unwrap_or(""))
+            } else {
+                gf_branch_base(repo, kind, &new)
+            };
+            if let Err(e) = gf_diff_branches(repo, &base, &new, &opts) {
+                say!("Diff {} failed: {}", new, e.message());
+                return None;
+            }
+        }
+        ("rebase", Some(match_sub1)) => {
+            let result = if match_sub1.is_present("abort") {
+                remove_finish_state(repo);
+                rebase::abort(repo).map(|()| {
+                    say!("Rebase aborted");
+                    rebase::Outcome::Done
+                })
+            } else if match_sub1.is_present("continue") {
+                rebase::resume(repo)
+            } else if match_sub1.is_present("skip") {
+                rebase::skip(repo)
+            } else {
+                let br_name = match match_sub1.value_of(name_arg) {
+                    Some(br) => gf_branch_name(repo, kind, br),
+                    None => match repo.head().ok().as_ref().and_then(|h| h.shorthand())
+                        .filter(|br| gf_kind_of(repo, br).map(|(k, _)| k) == Some(kind)) {
+                        Some(br) => br.to_owned(),
+                        None => {
+                            say!("Not on a {} branch, give the {} name", kind, kind);
+                            return None;
+                        }
+                    },
+                };
+                let opts = rebase::Options {
+                    interactive: match_sub1.is_present("interactive"),
+                    merges: match_sub1.is_present("rebase-merges"),
+                    autosquash: if match_sub1.is_present("autosquash") {
+                        true
+                    } else if match_sub1.is_present("no-autosquash") {
+                        false
+                    } else {
+                        gf_autosquash(repo)
+                    },
+                };
+                gf_rebase(repo, &br_name, &gf_branch_base(repo, kind, &br_name), &opts)
+            };
+            match result {
+                Ok(rebase::Outcome::Done) => {
+                    if !match_sub1.is_present("abort") {
+                        say!("Rebase {} successfully", repo.head().ok().as_ref().and_then(|h| h.shorthand()).unwrap_or(""));
+                        if read_finish_state(repo).is_some() {
+                            say!("Run \"git flow {} finish --continue\" to finish it", kind);
+                        }
+                    }
+                }
+                Ok(rebase::Outcome::Stopped(message)) => {
+                    say!("{}", rebase_stopped(kind, &message, "rebase").message());
+                    return None;
+                }
+                Err(e) => {
+                    say!("Rebase failed: {}", e.message());
+                    return None;
+                }
+            }
+        }
+        ("checkout", Some(match_sub1)) => {
+            let br_name = &gf_branch_name(repo, kind, match_sub1.value_of(name_arg).unwrap());
+            let result = gf_worktree_path(repo, match_sub1, br_name).and_then(|path| match path {
+                Some(path) => worktree::add(repo, br_name, &path).map(|()| {
+                    say!("Created the worktree {}", path.display());
+                }),
+                None => checkout_branch(repo, br_name),
+            });
+            match result {
+                Ok(()) => say!("Checkout to {} successfully", br_name),
+                Err(e) => {
+                    say!("Checkout to {} failed: {}", br_name, e.message());
+                    return None;
+                },
+            }
+        }
+        ("delete", Some(match_sub1)) => {
+            let br_name = &gf_branch_name(repo, kind, match_sub1.value_of(name_arg).unwrap());
+            match delete_branch(repo, br_name) {
+                Ok(()) => say!("Delete {} successfully", br_name),
+                Err(e) => {
+                    say!("Delete {} failed: {}", br_name, e.message());
+                    return None;
+                },
+            }
+        }
+        _ => {}
+    }
+
+    Some(0)
+}
+
+// Global options may be given at any level of the command line, the
+// innermost one wins.
+fn global_value<'a>(matches: &'a ArgMatches, name: &str) -> Option<&'a str> {
+    let mut value = matches.value_of(name);
+    let mut current = matches;
+    while let (_, Some(sub)) = current.subcommand() {
+        value = sub.value_of(name).or(value);
+        current = sub;
+    }
+    value
+}
+
+fn global_present(matches: &ArgMatches, name: &str) -> bool {
+    let mut present = matches.is_present(name);
+    let mut current = matches;
+    while let (_, Some(sub)) = current.subcommand() {
+        present |= sub.is_present(name);
+        current = sub;
+    }
+    present
+}
+
+fn gf_run() -> i32 {
+    let matches = App::new("git-flow")
+        .version("0.5.0")
+        .author("Jason Wang <wang_borong@163.com>")
+        .about("Workflow in git")
+        .arg(Arg::with_name("directory")
+            .short("C")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("path")
+            .help("Run as if git flow was started in <path>, each one relative to the one before"))
+        .arg(Arg::with_name("format")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["human", "json", "porcelain"])
+            .global(true)
+            .help("Print the result of config, list, status, start, finish and publish as a document"))
+        .arg(Arg::with_name("porcelain")
+            .long("porcelain")
+            .global(true)
+            .help("Same as --format porcelain, one \"key value\" line per field"))
+        .arg(Arg::with_name("progress")
+            .long("progress")
+            .takes_value(true)
+            .possible_values(&["auto", "quiet", "bar", "plain", "json"])
+            .global(true)
+            .help("How to report fetch and push progress, auto picks bar on a terminal and plain otherwise"))
+        .arg(Arg::with_name("color")
+            .long("color")
+            .takes_value(true)
+            .possible_values(&["auto", "always", "never"])
+            .global(true)
+            .help("When to color diff and log output, auto follows color.diff and color.ui"))
+        .arg(Arg::with_name("no-pager")
+            .long("no-pager")
+            .global(true)
+            .help("Do not pipe diff and log output into a pager"))
+        // Init subcommand
+        .subcommand(SubCommand::with_name("init")
+            .about("Setup a git repository for git flow usage.")
+            .arg(Arg::with_name("init_path")
+                .help("Path to be initialized")))
+        // Config subcommand
         .subcommand(SubCommand::with_name("config")
             .about("Show the git-flow configurations")
-            )
+            .arg(Arg::with_name("local")
+                .long("local")
+                .global(true)
+                .help("Use the repository configuration"))
+            .arg(Arg::with_name("global")
+                .long("global")
+                .global(true)
+                .conflicts_with("local")
+                .help("Use the user configuration, ~/.gitconfig"))
+            .arg(Arg::with_name("system")
+                .long("system")
+                .global(true)
+                .conflicts_with_all(&["local", "global"])
+                .help("Use the system configuration, /etc/gitconfig"))
+            .arg(Arg::with_name("file")
+                .long("file")
+                .short("f")
+                .takes_value(true)
+                .value_name("path")
+                .global(true)
+                .conflicts_with_all(&["local", "global", "system"])
+                .help("Use the given configuration file"))
+            .subcommand(SubCommand::with_name("get")
+                .about("Print one setting")
+                .arg(Arg::with_name("key")
+                    .help("A full gitflow.* key or master, develop, origin or a prefix name like feature")
+                    .required(true)
+                    .index(1)))
+            .subcommand(SubCommand::with_name("set")
+                .about("Change one setting")
+                .arg(Arg::with_name("key")
+                    .help("A full gitflow.* key or master, develop, origin or a prefix name like feature")
+                    .required(true)
+                    .index(1))
+                .arg(Arg::with_name("value")
+                    .help("The new value")
+                    .required(true)
+                    .index(2))
+                .arg(Arg::with_name("rename")
+                    .long("rename")
+                    .help("Rename the branches of a changed prefix without asking")))
+            .subcommand(SubCommand::with_name("unset")
+                .about("Remove one setting")
+                .arg(Arg::with_name("key")
+                    .help("A full gitflow.* key or master, develop, origin or a prefix name like feature")
+                    .required(true)
+                    .index(1)))
+            .subcommand(SubCommand::with_name("list")
+                .about("Print all the gitflow.* settings")))
+        // Status subcommand
+        .subcommand(SubCommand::with_name("status")
+            .about("Show the git-flow state of the current branch and repository"))
+        // Cleanup subcommand
+        .subcommand(SubCommand::with_name("cleanup")
+            .about("Delete flow branches which are merged, idle or whose upstream is gone")
+            .arg(Arg::with_name("days")
+                .long("days")
+                .takes_value(true)
+                .value_name("N")
+                .help("Also clean up branches without commits for N days"))
+            .arg(Arg::with_name("kind")
+                .long("kind")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .possible_values(&FLOW_KINDS)
+                .help("Only look at branches of this kind, all but support by default"))
+            .arg(Arg::with_name("remote")
+                .long("remote")
+                .help("Delete the branches on the flow remote too"))
+            .arg(Arg::with_name("yes")
+                .short("y")
+                .long("yes")
+                .help("Delete without asking"))
+            .arg(Arg::with_name("dry-run")
+                .short("n")
+                .long("dry-run")
+                .help("Only show what would be deleted")))
+        // Push-pending subcommand
+        .subcommand(SubCommand::with_name("push-pending")
+            .about("Push the refs a previous finish --push could not push"))
         // Feature subcommand
         .subcommand(SubCommand::with_name("feature")
             .about("Manage your feature branches.")
@@ -621,15 +2881,27 @@ fn gf_run() {
                 .arg(Arg::with_name("feature_name")
                     .help("The new feature to be started")
                     .required(true)
-                    .index(1)))
+                    .index(1))
+                .arg(Arg::with_name("base_branch")
+                    .help("The branch, tag, commit or remote branch to start from, develop by default")
+                    .index(2))
+                .args(&start_args()))
             .subcommand(SubCommand::with_name("finish")
                 .about("Finish feature branch")
                 .arg(Arg::with_name("feature_name")
                     .help("The feature to be finished")
-                    .required(true)
-                    .index(1)))
+                    .required_unless("continue")
+                    .index(1))
+                .args(&finish_args()))
+            .subcommand(SubCommand::with_name("log")
+                .about("Show the commits of a feature branch which are not in its base branch.")
+                .arg(Arg::with_name("feature_name")
+                    .help("The feature to be shown, the current one by default")
+                    .index(1))
+                .args(&log_args()))
             .subcommand(SubCommand::with_name("list")
-                .about("Lists all the existing feature branches in the local repository"))
+                .about("Lists all the existing feature branches in the local repository")
+                .args(&list_args()))
             .subcommand(SubCommand::with_name("publish")
                 .about("Publish feature branch on origin.")
                 .arg(Arg::with_name("feature_name")
@@ -643,24 +2915,21 @@ fn gf_run() {
             .subcommand(SubCommand::with_name("diff")
                 .about("Show all changes in feature branch that are not in the base branch.")
                 .arg(Arg::with_name("feature_name")
-                    .help("The feature to be checked")))
+                    .help("The feature to be checked"))
+                .args(&diff_args()))
             .subcommand(SubCommand::with_name("rebase")
                 .about("Rebase feature on develop")
-                .arg(Arg::with_name("interactive")
-                    .short("i")
-                    .help("Do an interactive rebase"))
-                .arg(Arg::with_name("rebase-merges")
-                    .short("r")
-                    .help("Preserve merges"))
                 .arg(Arg::with_name("feature_name")
-                    .help("The feature branch to be rebased")
-                    .index(1)))
+                    .help("The feature branch to be rebased, the current one by default")
+                    .index(1))
+                .args(&rebase_args()))
             .subcommand(SubCommand::with_name("checkout")
                 .about("Switch to feature branch")
                 .arg(Arg::with_name("feature_name")
                     .help("The feature name to be checked out")
                     .required(true)
-                    .index(1)))
+                    .index(1))
+                .arg(worktree_arg()))
             .subcommand(SubCommand::with_name("delete")
                 .about("Delete a given feature branch")
                 .arg(Arg::with_name("feature_name")
@@ -676,17 +2945,29 @@ fn gf_run() {
                 .arg(Arg::with_name("release_name")
                     .help("work on a release branch")
                     .required(true)
-                    .index(1)))
+                    .index(1))
+                .arg(Arg::with_name("base_branch")
+                    .help("The branch, tag, commit or remote branch to start from, develop by default")
+                    .index(2))
+                .args(&start_args()))
             .subcommand(SubCommand::with_name("finish")
                 .about("release finish command")
                 .arg(Arg::with_name("release_name")
                     .help("work off a release branch")
-                    .required(true)
-                    .index(1)))
+                    .required_unless("continue")
+                    .index(1))
+                .args(&finish_args()))
+            .subcommand(SubCommand::with_name("log")
+                .about("Show the commits of a release branch which are not in its base branch.")
+                .arg(Arg::with_name("release_name")
+                    .help("The release to be shown, the current one by default")
+                    .index(1))
+                .args(&log_args()))
             .subcommand(SubCommand::with_name("list")
-                .about("release list command"))
+                .about("release list command")
+                .args(&list_args()))
             .subcommand(SubCommand::with_name("publish")
-                .about("Publish release branch on origin.")
+                .about("Publish release branch on origin, and the production branch and version tag on gitflow.release.remotes.")
                 .arg(Arg::with_name("release_name")
                     .help("The release to be published")))
             .subcommand(SubCommand::with_name("track")
@@ -710,23 +2991,35 @@ fn gf_run() {
                 .arg(Arg::with_name("hotfix_name")
                     .help("work on a hotfix branch")
                     .required(true)
-                    .index(1)))
+                    .index(1))
+                .arg(Arg::with_name("base_branch")
+                    .help("The branch, tag, commit or remote branch to start from, develop by default")
+                    .index(2))
+                .args(&start_args()))
             .subcommand(SubCommand::with_name("finish")
                 .about("hotfix finish command")
                 .arg(Arg::with_name("hotfix_name")
                     .help("work off a hotfix branch")
-                    .required(true)
-                    .index(1)))
+                    .required_unless("continue")
+                    .index(1))
+                .args(&finish_args()))
+            .subcommand(SubCommand::with_name("log")
+                .about("Show the commits of a hotfix branch which are not in its base branch.")
+                .arg(Arg::with_name("hotfix_name")
+                    .help("The hotfix to be shown, the current one by default")
+                    .index(1))
+                .args(&log_args()))
             .subcommand(SubCommand::with_name("list")
-                .about("hotfix list command"))
+                .about("hotfix list command")
+                .args(&list_args()))
             .subcommand(SubCommand::with_name("publish")
-                .about("Publish feature branch on origin.")
-                .arg(Arg::with_name("feature_name")
-                    .help("The feature to be published")))
+                .about("Publish hotfix branch on origin.")
+                .arg(Arg::with_name("hotfix_name")
+                    .help("The hotfix to be published")))
             .subcommand(SubCommand::with_name("delete")
-                .about("Delete a given feature branch")
-                .arg(Arg::with_name("feature_name")
-                    .help("The feature branch to be deleted")
+                .about("Delete a given hotfix branch")
+                .arg(Arg::with_name("hotfix_name")
+                    .help("The hotfix branch to be deleted")
                     .required(true)
                     .index(1)))
         )
@@ -737,15 +3030,27 @@ fn gf_run() {
                 .arg(Arg::with_name("bugfix_name")
                     .help("work on a bugfix branch")
                     .required(true)
-                    .index(1)))
+                    .index(1))
+                .arg(Arg::with_name("base_branch")
+                    .help("The branch, tag, commit or remote branch to start from, develop by default")
+                    .index(2))
+                .args(&start_args()))
             .subcommand(SubCommand::with_name("finish")
                 .about("bugfix finish command")
                 .arg(Arg::with_name("bugfix_name")
                     .help("work off a bugfix branch")
-                    .required(true)
-                    .index(1)))
+                    .required_unless("continue")
+                    .index(1))
+                .args(&finish_args()))
+            .subcommand(SubCommand::with_name("log")
+                .about("Show the commits of a bugfix branch which are not in its base branch.")
+                .arg(Arg::with_name("bugfix_name")
+                    .help("The bugfix to be shown, the current one by default")
+                    .index(1))
+                .args(&log_args()))
             .subcommand(SubCommand::with_name("list")
-                .about("bugfix list command"))
+                .about("bugfix list command")
+                .args(&list_args()))
             .subcommand(SubCommand::with_name("publish")
                 .about("Publish bugfix branch on origin.")
                 .arg(Arg::with_name("bugfix_name")
@@ -759,24 +3064,21 @@ fn gf_run() {
             .subcommand(SubCommand::with_name("diff")
                 .about("Show all changes in bugfix branch that are not in the base branch.")
                 .arg(Arg::with_name("bugfix_name")
-                    .help("The bugfix to be checked")))
+                    .help("The bugfix to be checked"))
+                .args(&diff_args()))
             .subcommand(SubCommand::with_name("rebase")
                 .about("Rebase bugfix on develop")
-                .arg(Arg::with_name("interactive")
-                    .short("i")
-                    .help("Do an interactive rebase"))
-                .arg(Arg::with_name("rebase-merges")
-                    .short("r")
-                    .help("Preserve merges"))
                 .arg(Arg::with_name("bugfix_name")
-                    .help("The bugfix branch to be rebased")
-                    .index(1)))
+                    .help("The bugfix branch to be rebased, the current one by default")
+                    .index(1))
+                .args(&rebase_args()))
             .subcommand(SubCommand::with_name("checkout")
                 .about("Switch to bugfix branch")
                 .arg(Arg::with_name("bugfix_name")
                     .help("The bugfix name to be checked out")
                     .required(true)
-                    .index(1)))
+                    .index(1))
+                .arg(worktree_arg()))
             .subcommand(SubCommand::with_name("delete")
                 .about("Delete a given bugfix branch")
                 .arg(Arg::with_name("bugfix_name")
@@ -788,366 +3090,832 @@ fn gf_run() {
             .about("git flow support")
             .subcommand(SubCommand::with_name("start")
                 .about("support start command")
-                .arg(Arg::with_name("bugfix_name")
-                    .help("work on a bugfix branch")
+                .arg(Arg::with_name("support_name")
+                    .help("work on a support branch")
                     .required(true)
                     .index(1))
                 .arg(Arg::with_name("base_branch")
                     .help("the based branch which a support starts from")
                     .required(true)
-                    .index(2)))
+                    .index(2))
+                .args(&start_args()))
             .subcommand(SubCommand::with_name("list")
-                .about("bugfix list command"))
+                .about("support list command")
+                .args(&list_args()))
         )
         // ...
         .get_matches();
 
+    if let Some(format) = global_value(&matches, "format") {
+        output::set_format(output::Format::from_name(format).unwrap());
+    }
+    if global_present(&matches, "porcelain") {
+        output::set_format(output::Format::Porcelain);
+    }
+    if let Some(mode) = global_value(&matches, "progress") {
+        progress::set_mode(progress::ProgressMode::from_name(mode).unwrap());
+    }
+    if let Some(mode) = global_value(&matches, "color") {
+        color::set_mode(color::ColorMode::from_name(mode).unwrap());
+    }
+    if global_present(&matches, "no-pager") {
+        pager::disable();
+    }
+    for dir in matches.values_of("directory").into_iter().flatten() {
+        if let Err(e) = env::set_current_dir(dir) {
+            say!("Cannot change to '{}': {}", dir, e);
+            return 1;
+        }
+    }
+
     // Init
     if let Some(matches) = matches.subcommand_matches("init") {
-        let path = matches.value_of("init_path").unwrap_or(".");
-        match gf_init(&path) {
-            Ok(()) => println!("Init {} Successfully", path),
-            Err(_) => {
-                println!("Init {} failed", path);
-                return;
+        let path = matches.value_of("init_path");
+        match gf_init(path) {
+            Ok(()) => say!("Init {} Successfully", path.unwrap_or(".")),
+            Err(e) => {
+                say!("Init {} failed: {}", path.unwrap_or("."), e.message());
+                return 1;
             },
         }
     }
 
     // Config
-    if let Some(_matches) = matches.subcommand_matches("config") {
-        gf_config();
-    }
-
-    // Feature
-    if let Some(match_sub0) = matches.subcommand_matches("feature") {
-        // start
-        if let Some(match_sub1) = match_sub0.subcommand_matches("start") {
-            let br = match_sub1.value_of("feature_name").unwrap();
-            let repo = Repository::open(".").expect("Can get repo");
-            checkout_branch(&repo, "develop").expect("checkout branch failed");
-            match gf_subcmd("feature", "start", &repo, "develop", br) {
-                Ok(()) => println!("Start feature {} successfully", br),
-                Err(_) => {
-                    println!("Start feature {} failed", br);
-                    return;
-                },
-            }
-        }
-        // finish
-        if let Some(match_sub1) = match_sub0.subcommand_matches("finish") {
-            let br = match_sub1.value_of("feature_name").unwrap();
-            let repo = Repository::open(".").expect("Can get repo");
-            match gf_subcmd("feature", "finish", &repo, "develop", br) {
-                Ok(()) => println!("Run feature {} successfully", br),
-                Err(_) => {
-                    println!("Run feature {} failed", br);
-                    return;
-                },
-            }
-        }
-        // list
-        if let Some(_) = match_sub0.subcommand_matches("list") {
-            gf_list_branch("feature");
-        }
-        // publish
-        if let Some(match_sub1) = match_sub0.subcommand_matches("publish") {
-            if match_sub1.is_present("feature_name") {
-                let tmp_br = match_sub1.value_of("feature_name").unwrap();
-                print!("Username: ");
-                let _ = stdout().flush();
-                let mut user = String::new();
-                stdin().read_line(&mut user).expect("Get user failed");
-                let pass = rpassword::read_password_from_tty(Some("Password: ")).unwrap();
-                gf_publish(Some(&("feature/".to_owned() + tmp_br)), &user, &pass);
-            } else {
-                gf_publish(None, "", "");
+    if let Some(matches) = matches.subcommand_matches("config") {
+        let scope = if let Some(path) = global_value(matches, "file") {
+            ConfigScope::File(PathBuf::from(path))
+        } else if global_present(matches, "global") {
+            ConfigScope::Global
+        } else if global_present(matches, "system") {
+            ConfigScope::System
+        } else if global_present(matches, "local") {
+            ConfigScope::Local
+        } else {
+            ConfigScope::Default
+        };
+        let repo = gf_open_repo().ok();
+        let repo = repo.as_ref();
+        let (command, sub) = matches.subcommand();
+        let key = sub.and_then(|sub| sub.value_of("key")).map(|name| (name, gf_config_key(name)));
+        let result = match (command, key) {
+            (_, Some((name, None))) => Err(Error::from_str(&format!("Unknown git-flow setting '{}'", name))),
+            ("get", Some((_, Some(key)))) => {
+                gf_config_open(repo, &scope, false).and_then(|config| gf_config_get(&config, &key))
             }
-        }
-        // track
-        if let Some(match_sub1) = match_sub0.subcommand_matches("track") {
-            let br_name = match_sub1.value_of("feature_name")
-                .expect("No feature name input");
-            gf_track(&("feature/".to_owned() + br_name));
-        }
-        // diff
-        if let Some(match_sub1) = match_sub0.subcommand_matches("diff") {
-            if match_sub1.is_present("feature_name") {
-                let tmp_br = match_sub1.value_of("feature_name").unwrap();
-                gf_diff_branches("develop", Some(&("feature/".to_owned() + tmp_br)));
-            } else {
-                gf_diff_branches("develop", None);
+            ("set", Some((_, Some(key)))) => {
+                let sub = sub.unwrap();
+                gf_config_set(repo, &scope, &key, sub.value_of("value").unwrap(), sub.is_present("rename"))
             }
+            ("unset", Some((_, Some(key)))) => gf_config_unset(repo, &scope, &key),
+            ("list", _) => gf_config_open(repo, &scope, false).and_then(|config| gf_config_show_all(&config, &scope)),
+            _ => gf_config_open(repo, &scope, false).map(|config| gf_config(&config)),
+        };
+        if let Err(e) = result {
+            say!("Config {} failed: {}", if command.is_empty() { "show" } else { command }, e.message());
+            return 1;
         }
-        // rebase
-        if let Some(match_sub1) = match_sub0.subcommand_matches("rebase") {
-            let mut opt = None;
-            if match_sub1.is_present("interactive") {
-                opt = Some("--interactive");
-            } else if match_sub1.is_present("rebase-merges") {
-                opt = Some("--rebase-merges");
-            }
+    }
 
-            let br_name = "feature/".to_owned() + match_sub1.value_of("feature_name").unwrap();
-            gf_rebase(Some(&br_name), opt);
-        }
-        // checkout
-        if let Some(match_sub1) = match_sub0.subcommand_matches("checkout") {
-            let br = match_sub1.value_of("feature_name").unwrap();
-            let br_name = &("feature/".to_owned() + br);
-            let repo = Repository::open(".").expect("Not a git repository");
-            match checkout_branch(&repo, br_name) {
-                Ok(()) => println!("Checkout to {} successfully", br_name),
-                Err(_) => {
-                    println!("Checkout to {} failed", br_name);
-                    return;
-                },
-            }
-        }
-        //delete
-        if let Some(match_sub1) = match_sub0.subcommand_matches("delete") {
-            let br = match_sub1.value_of("feature_name").unwrap();
-            let br_name = &("feature/".to_owned() + br);
-            let repo = Repository::open(".").expect("Not a git repository");
-            match delete_branch(&repo, br_name) {
-                Ok(()) => println!("Delete {} successfully", br_name),
-                Err(_) => {
-                    println!("Delete {} failed", br_name);
-                    return;
-                },
+    // Push-pending
+    if let Some(_matches) = matches.subcommand_matches("push-pending") {
+        let repo = match gf_open_repo() {
+            Ok(repo) => repo,
+            Err(e) => {
+                say!("Push-pending failed: {}", e.message());
+                return 1;
             }
+        };
+        if !gf_push_pending(&repo) {
+            return 1;
         }
     }
 
-    // Release
-    if let Some(match_sub0) = matches.subcommand_matches("release") {
-        if let Some(match_sub1) = match_sub0.subcommand_matches("start") {
-            let br = match_sub1.value_of("release_name").unwrap();
-            let repo = Repository::open(".").expect("Not a git repository");
-            checkout_branch(&repo, "develop").expect("checkout branch failed");
-            match gf_subcmd("release", "start", &repo, "develop", br) {
-                Ok(()) => println!("Run release {} successfully", br),
-                Err(_) => {
-                    println!("Run release {} failed", br);
-                    return;
-                },
+    // Cleanup
+    if let Some(matches) = matches.subcommand_matches("cleanup") {
+        let repo = match gf_open_repo() {
+            Ok(repo) => repo,
+            Err(e) => {
+                say!("Cleanup failed: {}", e.message());
+                return 1;
             }
-        }
-        if let Some(match_sub1) = match_sub0.subcommand_matches("finish") {
-            let br = match_sub1.value_of("release_name").unwrap();
-            let repo = Repository::open(".").expect("Not a git repository");
-            match gf_subcmd("release", "finish", &repo, "develop", br) {
-                Ok(()) => println!("Run release {} successfully", br),
-                Err(_) => {
-                    println!("Run release {} failed", br);
-                    return;
-                },
-            }
-        }
-        if let Some(_) = match_sub0.subcommand_matches("list") {
-            gf_list_branch("release");
-        }
-        // publish
-        if let Some(match_sub1) = match_sub0.subcommand_matches("publish") {
-            if match_sub1.is_present("release_name") {
-                let tmp_br = match_sub1.value_of("release_name").unwrap();
-                let user = get_input("Username: ");
-                let pass = rpassword::read_password_from_tty(Some("Password: ")).unwrap();
-                gf_publish(Some(&("release/".to_owned() + tmp_br)), &user, &pass);
-            } else {
-                gf_publish(None, "", "");
+        };
+        let days = match matches.value_of("days").map(|days| days.parse::<i64>()) {
+            Some(Ok(days)) => Some(days),
+            Some(Err(_)) => {
+                say!("Cleanup failed: --days needs a number of days");
+                return 1;
             }
-        }
-        // track
-        if let Some(match_sub1) = match_sub0.subcommand_matches("track") {
-            let br_name = match_sub1.value_of("release_name")
-                .expect("No release name input");
-            gf_track(&("release/".to_owned() + br_name));
-        }
-        //delete
-        if let Some(match_sub1) = match_sub0.subcommand_matches("delete") {
-            let br = match_sub1.value_of("release_name").unwrap();
-            let br_name = &("release/".to_owned() + br);
-            let repo = Repository::open(".").expect("Not a git repository");
-            match delete_branch(&repo, br_name) {
-                Ok(()) => println!("Delete {} successfully", br_name),
-                Err(_) => {
-                    println!("Delete {} failed", br_name);
-                    return;
-                },
+            None => None,
+        };
+        let opts = CleanupOpts {
+            kinds: matches.values_of("kind")
+                .map(|v| v.collect())
+                .unwrap_or_else(|| vec!["feature", "bugfix", "release", "hotfix"]),
+            days,
+            remote: matches.is_present("remote"),
+            yes: matches.is_present("yes"),
+            dry_run: matches.is_present("dry-run"),
+        };
+        match gf_cleanup(&repo, &opts) {
+            Ok(true) => {}
+            Ok(false) => return 1,
+            Err(e) => {
+                say!("Cleanup failed: {}", e.message());
+                return 1;
             }
         }
     }
 
-    // Hotfix
-    if let Some(match_sub0) = matches.subcommand_matches("hotfix") {
-        if let Some(match_sub1) = match_sub0.subcommand_matches("start") {
-            let br = match_sub1.value_of("hotfix_name").unwrap();
-            let repo = Repository::open(".").expect("Not a git repository");
-            checkout_branch(&repo, "develop").expect("checkout branch failed");
-            match gf_subcmd("hotfix", "start", &repo, "develop", br) {
-                Ok(()) => println!("Run hotfix {} successfully", br),
-                Err(_) => {
-                    println!("Run hotfix {} failed", br);
-                    return;
-                },
-            }
-        }
-        if let Some(match_sub1) = match_sub0.subcommand_matches("finish") {
-            let br = match_sub1.value_of("hotfix_name").unwrap();
-            let repo = Repository::open(".").expect("Not a git repository");
-            match gf_subcmd("hotfix", "finish", &repo, "develop", br) {
-                Ok(()) => println!("Run hotfix {} successfully", br),
-                Err(_) => {
-                    println!("Run hotfix {} failed", br);
-                    return;
-                },
-            }
-        }
-        if let Some(_) = match_sub0.subcommand_matches("list") {
-            gf_list_branch("hotfix");
-        }
-        // publish
-        if let Some(match_sub1) = match_sub0.subcommand_matches("publish") {
-            if match_sub1.is_present("hotfix_name") {
-                let tmp_br = match_sub1.value_of("hotfix_name").unwrap();
-                let user = get_input("Username: ");
-                let pass = rpassword::read_password_from_tty(Some("Password: ")).unwrap();
-                gf_publish(Some(&("hotfix/".to_owned() + tmp_br)), &user, &pass);
-            } else {
-                gf_publish(None, "", "");
-            }
-        }
-        //delete
-        if let Some(match_sub1) = match_sub0.subcommand_matches("delete") {
-            let br = match_sub1.value_of("hotfix_name").unwrap();
-            let br_name = &("hotfix/".to_owned() + br);
-            let repo = Repository::open(".").expect("Not a git repository");
-            match delete_branch(&repo, br_name) {
-                Ok(()) => println!("Delete {} successfully", br_name),
-                Err(_) => {
-                    println!("Delete {} failed", br_name);
-                    return;
-                },
+    // Status
+    if let Some(_matches) = matches.subcommand_matches("status") {
+        let repo = match gf_open_repo() {
+            Ok(repo) => repo,
+            Err(e) => {
+                say!("Status failed: {}", e.message());
+                return 1;
             }
+        };
+        if let Err(e) = gf_status(&repo) {
+            say!("Status failed: {}", e.message());
+            return 1;
         }
     }
 
-    // Bugfix
-    if let Some(match_sub0) = matches.subcommand_matches("bugfix") {
-        if let Some(match_sub1) = match_sub0.subcommand_matches("start") {
-            let br = match_sub1.value_of("bugfix_name").unwrap();
-            let repo = Repository::open(".").expect("Not a git repository");
-            checkout_branch(&repo, "develop").expect("checkout branch failed");
-            match gf_subcmd("bugfix", "start", &repo, "develop", br) {
-                Ok(()) => println!("Run bugfix {} successfully", br),
-                Err(_) => {
-                    println!("Run bugfix {} failed", br);
-                    return;
-                },
-            }
-        }
-        if let Some(match_sub1) = match_sub0.subcommand_matches("finish") {
-            let br = match_sub1.value_of("bugfix_name").unwrap();
-            let repo = Repository::open(".").expect("Not a git repository");
-            match gf_subcmd("bugfix", "finish", &repo, "develop", br) {
-                Ok(()) => println!("Run bugfix {} successfully", br),
-                Err(_) => {
-                    println!("Run bugfix {} failed", br);
-                    return;
-                },
-            }
-        }
-        if let Some(_) = match_sub0.subcommand_matches("list") {
-            gf_list_branch("bugfix");
-        }
-        // publish
-        if let Some(match_sub1) = match_sub0.subcommand_matches("publish") {
-            if match_sub1.is_present("bugfix_name") {
-                let tmp_br = match_sub1.value_of("bugfix_name").unwrap();
-                let user = get_input("Username: ");
-                let pass = rpassword::read_password_from_tty(Some("Password: ")).unwrap();
-                gf_publish(Some(&("bugfix/".to_owned() + tmp_br)), &user, &pass);
-            } else {
-                gf_publish(None, "", "");
-            }
+    // Feature, release, hotfix, bugfix and support
+    for kind in &FLOW_KINDS {
+        if let Some(match_sub0) = matches.subcommand_matches(kind) {
+            return gf_run_flow(kind, match_sub0);
         }
-        // track
-        if let Some(match_sub1) = match_sub0.subcommand_matches("track") {
-            let br_name = match_sub1.value_of("bugfix_name")
-                .expect("No bugfix name input");
-            gf_track(&("bugfix/".to_owned() + br_name));
-        }
-        // diff
-        if let Some(match_sub1) = match_sub0.subcommand_matches("diff") {
-            if match_sub1.is_present("bugfix_name") {
-                let tmp_br = match_sub1.value_of("bugfix_name").unwrap();
-                gf_diff_branches("develop", Some(&("bugfix/".to_owned() + tmp_br)));
-            } else {
-                gf_diff_branches("develop", None);
-            }
-        }
-        // rebase
-        if let Some(match_sub1) = match_sub0.subcommand_matches("rebase") {
-            let mut opt = None;
-            if match_sub1.is_present("interactive") {
-                opt = Some("--interactive");
-            } else if match_sub1.is_present("rebase-merges") {
-                opt = Some("--rebase-merges");
-            }
+    }
 
-            let br_name = "bugfix/".to_owned() + match_sub1.value_of("bugfix_name").unwrap();
-            gf_rebase(Some(&br_name), opt);
-        }
-        // checkout
-        if let Some(match_sub1) = match_sub0.subcommand_matches("checkout") {
-            let br = match_sub1.value_of("bugfix_name").unwrap();
-            let br_name = &("bugfix/".to_owned() + br);
-            let repo = Repository::open(".").expect("Not a git repository");
-            match checkout_branch(&repo, br_name) {
-                Ok(()) => println!("Checkout to {} successfully", br_name),
-                Err(_) => {
-                    println!("Checkout to {} failed", br_name);
-                    return;
-                },
-            }
-        }
-        //delete
-        if let Some(match_sub1) = match_sub0.subcommand_matches("delete") {
-            let br = match_sub1.value_of("bugfix_name").unwrap();
-            let br_name = &("bugfix/".to_owned() + br);
-            let repo = Repository::open(".").expect("Not a git repository");
-            match delete_branch(&repo, br_name) {
-                Ok(()) => println!("Delete {} successfully", br_name),
-                Err(_) => {
-                    println!("Delete {} failed", br_name);
-                    return;
-                },
-            }
+    0
+}
+
+fn main() {
+    std::process::exit(gf_run());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Once;
+
+    use super::*;
+
+    // A repository under the temp dir which goes away with the test.
+    pub struct TempRepo {
+        pub repo: Repository,
+        dir: PathBuf,
+    }
+
+    impl Drop for TempRepo {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.dir).unwrap_or(());
         }
     }
 
-    // Support
-    if let Some(match_sub0) = matches.subcommand_matches("support") {
-        if let Some(match_sub1) = match_sub0.subcommand_matches("start") {
-            let br = match_sub1.value_of("support_name").unwrap();
-            let base_br = match_sub1.value_of("base_branch").unwrap();
-            let repo = Repository::open(".").expect("Not a git repository");
-            checkout_branch(&repo, base_br).expect("checkout branch failed");
-            match gf_subcmd("support", "start", &repo, base_br, br) {
-                Ok(()) => println!("Run support {} successfully", br),
-                Err(_) => {
-                    println!("Run support {} failed", br);
-                    return;
-                },
-            }
+    // An empty repository on master, with someone to commit as.
+    pub fn temp_repo(name: &str) -> TempRepo {
+        // the editor of the repository should be the one tests get
+        static EDITOR: Once = Once::new();
+        EDITOR.call_once(|| env::remove_var("GIT_EDITOR"));
+        let dir = env::temp_dir().join(format!("git-flow-test-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).unwrap_or(());
+        let repo = Repository::init_opts(&dir, RepositoryInitOptions::new().initial_head("master")).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Test").unwrap();
+        config.set_str("user.email", "test@example.com").unwrap();
+        // messages go through unedited
+        config.set_str("core.editor", "true").unwrap();
+        TempRepo { repo, dir }
+    }
+
+    // Commit file with content on HEAD, and check it out.
+    pub fn commit(repo: &Repository, file: &str, content: &str, msg: &str) -> Oid {
+        fs::write(repo.workdir().unwrap().join(file), content).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(file)).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = repo.signature().unwrap();
+        let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+        let parents: Vec<&Commit> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, msg, &tree, &parents).unwrap()
+    }
+
+    // master with one commit and develop with one more, develop checked out.
+    fn flow_repo(name: &str) -> TempRepo {
+        let temp = temp_repo(name);
+        let repo = &temp.repo;
+        commit(repo, "a", "a", "Initial commit");
+        create_checkout_branch(repo, "develop", Some("master"), None).unwrap();
+        commit(repo, "b", "b", "Develop");
+        temp
+    }
+
+    fn recorded_base(repo: &Repository, br_name: &str) -> Option<String> {
+        repo.config().unwrap().get_string(&format!("gitflow.branch.{}.base", br_name)).ok()
+    }
+
+    #[test]
+    fn start_records_the_base_of_features() {
+        let temp = flow_repo("start-feature");
+        let repo = &temp.repo;
+        gf_start(repo, "feature", "develop", "one", None).unwrap();
+        gf_start(repo, "feature", "feature/one", "two", None).unwrap();
+
+        assert_eq!(recorded_base(repo, "feature/one").as_deref(), Some("develop"));
+        assert_eq!(recorded_base(repo, "feature/two").as_deref(), Some("feature/one"));
+        assert_eq!(gf_branch_base(repo, "feature", "feature/two"), "feature/one");
+    }
+
+    #[test]
+    fn start_never_records_master() {
+        let temp = flow_repo("start-master");
+        let repo = &temp.repo;
+        gf_start(repo, "feature", "master", "one", None).unwrap();
+        gf_start(repo, "hotfix", "master", "1.0.1", None).unwrap();
+
+        assert_eq!(recorded_base(repo, "feature/one"), None);
+        assert_eq!(gf_branch_base(repo, "feature", "feature/one"), "develop");
+        assert_eq!(recorded_base(repo, "hotfix/1.0.1"), None);
+        assert_eq!(gf_branch_base(repo, "hotfix", "hotfix/1.0.1"), "develop");
+    }
+
+    #[test]
+    fn start_leaves_releases_to_develop() {
+        let temp = flow_repo("start-release");
+        let repo = &temp.repo;
+        gf_start(repo, "feature", "develop", "one", None).unwrap();
+        gf_start(repo, "release", "feature/one", "1.0", None).unwrap();
+
+        assert_eq!(recorded_base(repo, "release/1.0"), None);
+        // a base recorded by hand doesn't count either
+        repo.config().unwrap().set_str("gitflow.branch.release/1.0.base", "feature/one").unwrap();
+        assert_eq!(gf_branch_base(repo, "release", "release/1.0"), "develop");
+    }
+
+    fn init_here(repo: &Repository) {
+        gf_init(Some(repo.workdir().unwrap().to_str().unwrap())).unwrap();
+    }
+
+    #[test]
+    fn init_sets_up_an_existing_repository_on_its_branch() {
+        let temp = temp_repo("init-existing");
+        let repo = &temp.repo;
+        repo.set_head("refs/heads/main").unwrap();
+        let main = commit(repo, "a", "a", "Initial commit");
+        repo.config().unwrap().set_str("gitflow.prefix.feature", "f/").unwrap();
+        init_here(repo);
+
+        assert_eq!(gf_master(repo), "main");
+        assert_eq!(repo.refname_to_id("refs/heads/develop").unwrap(), main);
+        assert_eq!(repo.head().unwrap().name(), Some("refs/heads/develop"));
+        assert_eq!(gf_branch_name(repo, "feature", "x"), "f/x");
+        assert_eq!(gf_branch_name(repo, "release", "x"), "release/x");
+        assert!(repo.find_branch("master", BranchType::Local).is_err());
+    }
+
+    #[test]
+    fn init_commits_on_the_unborn_branch() {
+        let temp = temp_repo("init-unborn");
+        let repo = &temp.repo;
+        repo.set_head("refs/heads/main").unwrap();
+        init_here(repo);
+
+        let main = repo.refname_to_id("refs/heads/main").unwrap();
+        assert_eq!(repo.refname_to_id("refs/heads/develop").unwrap(), main);
+        assert_eq!(gf_master(repo), "main");
+        assert_eq!(repo.head().unwrap().name(), Some("refs/heads/develop"));
+    }
+
+    #[test]
+    fn init_needs_the_configured_production_branch() {
+        let temp = temp_repo("init-missing");
+        let repo = &temp.repo;
+        commit(repo, "a", "a", "Initial commit");
+        repo.config().unwrap().set_str("gitflow.branch.master", "production").unwrap();
+
+        assert!(gf_init(Some(repo.workdir().unwrap().to_str().unwrap())).is_err());
+        assert!(repo.find_branch("develop", BranchType::Local).is_err());
+    }
+
+    #[test]
+    fn merge_policy_follows_merge_ff() {
+        let temp = flow_repo("merge-policy");
+        let repo = &temp.repo;
+        let mut config = repo.config().unwrap();
+        assert!(gf_merge_policy(repo, "feature") == MergePolicy::Ff);
+        config.set_str("merge.ff", "only").unwrap();
+        assert!(gf_merge_policy(repo, "feature") == MergePolicy::FfOnly);
+        config.set_str("merge.ff", "false").unwrap();
+        assert!(gf_merge_policy(repo, "feature") == MergePolicy::NoFf);
+        // the kind's own setting comes first
+        config.set_bool("gitflow.feature.finish.no-ff", false).unwrap();
+        assert!(gf_merge_policy(repo, "feature") == MergePolicy::Ff);
+        assert!(gf_merge_policy(repo, "bugfix") == MergePolicy::NoFf);
+    }
+
+    fn finish_opts(merge: Option<MergePolicy>) -> FinishOpts {
+        FinishOpts { push: false, squash: Some(false), rebase: false, merge }
+    }
+
+    #[test]
+    fn finish_of_a_branch_without_commits_merges_nothing() {
+        for policy in [None, Some(MergePolicy::FfOnly), Some(MergePolicy::NoFf)] {
+            let temp = flow_repo("finish-empty");
+            let repo = &temp.repo;
+            let develop = repo.refname_to_id("refs/heads/develop").unwrap();
+            gf_start(repo, "feature", "develop", "empty", None).unwrap();
+            gf_finish(repo, "feature", "develop", "empty", &finish_opts(policy)).unwrap();
+
+            assert_eq!(repo.refname_to_id("refs/heads/develop").unwrap(), develop);
+            assert!(repo.find_branch("feature/empty", BranchType::Local).is_err());
         }
-        if let Some(_) = match_sub0.subcommand_matches("list") {
-            gf_list_branch("support");
+    }
+
+    #[test]
+    fn finish_ff_only_refuses_to_merge() {
+        let temp = flow_repo("finish-ff-only");
+        let repo = &temp.repo;
+        gf_start(repo, "feature", "develop", "late", None).unwrap();
+        commit(repo, "c", "c", "Late");
+        checkout_branch(repo, "develop").unwrap();
+        let develop = commit(repo, "d", "d", "Moved on");
+
+        assert!(gf_finish(repo, "feature", "develop", "late", &finish_opts(Some(MergePolicy::FfOnly))).is_err());
+        assert_eq!(repo.refname_to_id("refs/heads/develop").unwrap(), develop);
+        assert!(repo.find_branch("feature/late", BranchType::Local).is_ok());
+    }
+
+    #[test]
+    fn finish_no_ff_merges_even_when_it_could_fast_forward() {
+        let temp = flow_repo("finish-no-ff");
+        let repo = &temp.repo;
+        let develop = repo.refname_to_id("refs/heads/develop").unwrap();
+        gf_start(repo, "feature", "develop", "small", None).unwrap();
+        let small = commit(repo, "c", "c", "Small");
+        gf_finish(repo, "feature", "develop", "small", &finish_opts(Some(MergePolicy::NoFf))).unwrap();
+
+        let merge = repo.find_commit(repo.refname_to_id("refs/heads/develop").unwrap()).unwrap();
+        assert_eq!(merge.parent_ids().collect::<Vec<_>>(), vec![develop, small]);
+    }
+
+    #[test]
+    fn finish_rebase_keeps_no_state_when_it_never_started() {
+        let temp = flow_repo("finish-rebase-dirty");
+        let repo = &temp.repo;
+        gf_start(repo, "feature", "develop", "dirty", None).unwrap();
+        commit(repo, "c", "c", "Dirty");
+        fs::write(repo.workdir().unwrap().join("c"), "changed").unwrap();
+        let opts = FinishOpts { rebase: true, ..finish_opts(None) };
+
+        assert!(gf_finish(repo, "feature", "develop", "dirty", &opts).is_err());
+        assert!(read_finish_state(repo).is_none());
+        assert!(repo.find_branch("feature/dirty", BranchType::Local).is_ok());
+    }
+
+    #[test]
+    fn finish_rebase_stops_and_continues() {
+        let temp = flow_repo("finish-rebase-stop");
+        let repo = &temp.repo;
+        gf_start(repo, "feature", "develop", "clash", None).unwrap();
+        commit(repo, "b", "feature", "Change b");
+        checkout_branch(repo, "develop").unwrap();
+        commit(repo, "b", "develop", "Change b on develop");
+        let opts = FinishOpts { rebase: true, ..finish_opts(None) };
+
+        assert!(gf_finish(repo, "feature", "develop", "clash", &opts).is_err());
+        let (kind, name, _) = read_finish_state(repo).unwrap();
+        assert_eq!((kind.as_str(), name.as_str()), ("feature", "clash"));
+
+        fs::write(repo.workdir().unwrap().join("b"), "both").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("b")).unwrap();
+        index.write().unwrap();
+        assert_eq!(gf_finish_continue(repo, "feature").unwrap(), ("clash".to_owned(), true));
+
+        assert!(read_finish_state(repo).is_none());
+        assert!(repo.find_branch("feature/clash", BranchType::Local).is_err());
+        assert_eq!(fs::read_to_string(repo.workdir().unwrap().join("b")).unwrap(), "both");
+    }
+
+    #[test]
+    fn latest_version_tag_within_a_branch() {
+        let temp = flow_repo("version-tag");
+        let repo = &temp.repo;
+        let master = repo.refname_to_id("refs/heads/master").unwrap();
+        let sig = repo.signature().unwrap();
+        repo.tag("1.0", &repo.find_object(master, None).unwrap(), &sig, "1.0", false).unwrap();
+        let develop = repo.head().unwrap().peel_to_commit().unwrap();
+        repo.tag_lightweight("1.1-rc1", develop.as_object(), false).unwrap();
+        repo.tag_lightweight("other", develop.as_object(), false).unwrap();
+        repo.config().unwrap().set_str("gitflow.prefix.versiontag", "1.").unwrap();
+
+        assert_eq!(gf_latest_version_tag(repo, Some(master)).as_deref(), Some("1.0"));
+        assert_eq!(gf_latest_version_tag(repo, None).as_deref(), Some("1.1-rc1"));
+    }
+
+    #[test]
+    fn prefix_rename_moves_recorded_bases() {
+        let temp = flow_repo("rename-bases");
+        let repo = &temp.repo;
+        gf_start(repo, "feature", "develop", "one", None).unwrap();
+        gf_start(repo, "feature", "feature/one", "two", None).unwrap();
+        checkout_branch(repo, "develop").unwrap();
+        repo.config().unwrap().set_str("gitflow.prefix.feature", "f/").unwrap();
+        gf_rename_prefix(repo, "feature", "feature/", "f/", false).unwrap();
+
+        assert_eq!(recorded_base(repo, "feature/one"), None);
+        assert_eq!(recorded_base(repo, "f/one").as_deref(), Some("develop"));
+        assert_eq!(recorded_base(repo, "f/two").as_deref(), Some("f/one"));
+        assert_eq!(gf_branch_base(repo, "feature", "f/two"), "f/one");
+    }
+
+    fn status(refspec: &str, error: Option<&str>) -> PushStatus {
+        PushStatus { refspec: refspec.to_owned(), error: error.map(str::to_owned) }
+    }
+
+    #[test]
+    fn pending_pushes_are_kept_until_they_go_through() {
+        let temp = flow_repo("push-pending");
+        let repo = &temp.repo;
+        record_pending_push(repo, "origin", &[
+            status("refs/heads/master:refs/heads/master", None),
+            status("refs/tags/1.0:refs/tags/1.0", Some("rejected")),
+        ]);
+        record_pending_push(repo, "mirror", &[status("refs/heads/master:refs/heads/master", Some("down"))]);
+        assert_eq!(read_pending_push(repo), vec![
+            ("origin".to_owned(), "refs/tags/1.0:refs/tags/1.0".to_owned()),
+            ("mirror".to_owned(), "refs/heads/master:refs/heads/master".to_owned()),
+        ]);
+
+        record_pending_push(repo, "origin", &[status("refs/tags/1.0:refs/tags/1.0", None)]);
+        record_pending_push(repo, "mirror", &[status("refs/heads/master:refs/heads/master", None)]);
+        assert!(read_pending_push(repo).is_empty());
+        assert!(!gf_state_path(repo, "push-pending").exists());
+    }
+
+    #[test]
+    fn deleting_a_branch_forgets_its_pending_pushes() {
+        let temp = flow_repo("push-pending-delete");
+        let repo = &temp.repo;
+        gf_start(repo, "release", "develop", "2.0", None).unwrap();
+        checkout_branch(repo, "develop").unwrap();
+        record_pending_push(repo, "mirror", &[
+            status("refs/heads/release/2.0:refs/heads/release/2.0", Some("down")),
+            status("refs/heads/master:refs/heads/master", Some("down")),
+        ]);
+        delete_branch(repo, "release/2.0").unwrap();
+
+        assert_eq!(read_pending_push(repo),
+                   vec![("mirror".to_owned(), "refs/heads/master:refs/heads/master".to_owned())]);
+    }
+
+    #[test]
+    fn flow_branches_are_sorted_without_prefix() {
+        let temp = flow_repo("flow-branches");
+        let repo = &temp.repo;
+        gf_start(repo, "feature", "develop", "zeta", None).unwrap();
+        gf_start(repo, "feature", "develop", "alpha", None).unwrap();
+        gf_start(repo, "bugfix", "develop", "other", None).unwrap();
+
+        let names: Vec<String> = gf_flow_branches(repo, "feature").unwrap().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["alpha", "zeta"]);
+    }
+
+    #[test]
+    fn upstream_ahead_behind_and_gone() {
+        let temp = flow_repo("upstream");
+        let repo = &temp.repo;
+        gf_start(repo, "feature", "develop", "x", None).unwrap();
+        let start = commit(repo, "c", "c", "One");
+        commit(repo, "d", "d", "Two");
+        repo.remote("origin", "/nonexistent").unwrap();
+        repo.reference("refs/remotes/origin/feature/x", start, false, "test").unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("branch.feature/x.remote", "origin").unwrap();
+        config.set_str("branch.feature/x.merge", "refs/heads/feature/x").unwrap();
+
+        let upstream = gf_upstream(repo, "refs/heads/feature/x").unwrap();
+        assert_eq!(upstream.text(), "origin/feature/x: ahead 1");
+        assert_eq!(gf_upstream(repo, "refs/heads/develop").map(|u| u.text()), None);
+
+        repo.find_reference("refs/remotes/origin/feature/x").unwrap().delete().unwrap();
+        assert_eq!(gf_upstream(repo, "refs/heads/feature/x").unwrap().text(), "origin/feature/x: gone");
+    }
+
+    #[test]
+    fn ahead_behind_and_age_texts() {
+        assert_eq!(ahead_behind_text(0, 0), "in sync");
+        assert_eq!(ahead_behind_text(2, 0), "ahead 2");
+        assert_eq!(ahead_behind_text(0, 1), "behind 1");
+        assert_eq!(ahead_behind_text(3, 4), "ahead 3, behind 4");
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        assert_eq!(time_ago(Time::new(now - 90, 0)), "1 minute ago");
+        assert_eq!(time_ago(Time::new(now - 3 * 60 * 60, 0)), "3 hours ago");
+        assert_eq!(time_ago(Time::new(now - 400 * 24 * 60 * 60, 0)), "1 year ago");
+    }
+
+    #[test]
+    fn kind_of_takes_the_longest_prefix() {
+        let temp = flow_repo("kind-of");
+        let repo = &temp.repo;
+        let mut config = repo.config().unwrap();
+        config.set_str("gitflow.prefix.bugfix", "feature/fix/").unwrap();
+        config.set_str("gitflow.prefix.support", "").unwrap();
+
+        assert_eq!(gf_kind_of(repo, "feature/login"), Some(("feature", "login".to_owned())));
+        assert_eq!(gf_kind_of(repo, "feature/fix/login"), Some(("bugfix", "login".to_owned())));
+        assert_eq!(gf_kind_of(repo, "hotfix/1.0.1"), Some(("hotfix", "1.0.1".to_owned())));
+        // an empty prefix would take every branch
+        assert_eq!(gf_kind_of(repo, "develop"), None);
+    }
+
+    #[test]
+    fn status_in_every_kind_of_place() {
+        let temp = flow_repo("status");
+        let repo = &temp.repo;
+        gf_status(repo).unwrap();
+        gf_start(repo, "feature", "develop", "x", None).unwrap();
+        gf_status(repo).unwrap();
+        let head = repo.head().unwrap().target().unwrap();
+        repo.set_head_detached(head).unwrap();
+        gf_status(repo).unwrap();
+
+        assert_eq!(repo_state_text(repo.state()), "clean");
+        assert_eq!(repo_state_text(RepositoryState::RebaseMerge), "rebase in progress");
+    }
+
+    #[test]
+    fn format_time_like_git_log() {
+        assert_eq!(format_time(Time::new(0, 0)), "Thu Jan 1 00:00:00 1970 +0000");
+        assert_eq!(format_time(Time::new(-1, 0)), "Wed Dec 31 23:59:59 1969 +0000");
+        assert_eq!(format_time(Time::new(951_782_400, 0)), "Tue Feb 29 00:00:00 2000 +0000");
+        assert_eq!(format_time(Time::new(1_234_567_890, 60)), "Sat Feb 14 00:31:30 2009 +0100");
+        assert_eq!(format_time(Time::new(1_234_567_890, -330)), "Fri Feb 13 18:01:30 2009 -0530");
+    }
+
+    fn changes(diff: &Diff) -> Vec<(char, String)> {
+        diff.deltas()
+            .map(|delta| (delta_status(delta.status()), delta.new_file().path().unwrap().display().to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn branch_diff_starts_at_the_fork_point() {
+        let tmp = flow_repo("branch-diff");
+        let repo = &tmp.repo;
+        commit(repo, "notes", "one\ntwo\nthree\nfour\n", "Notes");
+        create_checkout_branch(repo, "feature/login", Some("develop"), None).unwrap();
+        commit(repo, "login", "login", "Login");
+        fs::rename(repo.workdir().unwrap().join("notes"), repo.workdir().unwrap().join("docs")).unwrap();
+        let mut index = repo.index().unwrap();
+        index.remove_path(Path::new("notes")).unwrap();
+        index.write().unwrap();
+        commit(repo, "docs", "one\ntwo\nthree\nfour\n", "Move notes");
+        checkout_branch(repo, "develop").unwrap();
+        commit(repo, "later", "later", "Later on develop");
+
+        let diff = branch_diff(repo, "develop", "feature/login", &[]).unwrap();
+        assert_eq!(changes(&diff), [('R', "docs".to_owned()), ('A', "login".to_owned())]);
+        let diff = branch_diff(repo, "develop", "feature/login", &["login"]).unwrap();
+        assert_eq!(changes(&diff), [('A', "login".to_owned())]);
+    }
+
+    #[test]
+    fn config_values_are_validated() {
+        let tmp = flow_repo("config-validate");
+        let repo = Some(&tmp.repo);
+        assert!(gf_config_validate(repo, "gitflow.branch.develop", "next").is_ok());
+        assert!(gf_config_validate(repo, "gitflow.branch.develop", "bad..name").is_err());
+        assert!(gf_config_validate(repo, "gitflow.branch.develop", "master").is_err());
+        assert!(gf_config_validate(None, "gitflow.branch.develop", "master").is_ok());
+        assert!(gf_config_validate(repo, "gitflow.prefix.feature", "feat/").is_ok());
+        assert!(gf_config_validate(repo, "gitflow.prefix.feature", "").is_ok());
+        assert!(gf_config_validate(repo, "gitflow.prefix.feature", "feat").is_err());
+        assert!(gf_config_validate(repo, "gitflow.prefix.feature", "fe~at/").is_err());
+        assert!(gf_config_validate(repo, "gitflow.prefix.versiontag", "v").is_ok());
+        assert!(gf_config_validate(repo, "gitflow.prefix.versiontag", "v:").is_err());
+    }
+
+    #[test]
+    fn prefix_rename_moves_only_branches_of_the_kind() {
+        let tmp = flow_repo("config-rename-prefix");
+        let repo = &tmp.repo;
+        repo.config().unwrap().set_str("gitflow.prefix.bugfix", "feature/fix/").unwrap();
+        for br in ["feature/login", "feature/fix/typo", "feat/done"] {
+            create_checkout_branch(repo, br, Some("develop"), None).unwrap();
         }
+        checkout_branch(repo, "develop").unwrap();
+        repo.config().unwrap().set_str("gitflow.prefix.feature", "feat/").unwrap();
+
+        gf_rename_prefix(repo, "feature", "feature/", "feat/", false).unwrap();
+        let mut branches: Vec<String> = repo.branches(Some(BranchType::Local)).unwrap()
+            .map(|branch| branch.unwrap().0.name().unwrap().unwrap().to_owned())
+            .collect();
+        branches.sort();
+        assert_eq!(branches, ["develop", "feat/done", "feat/login", "feature/fix/typo", "master"]);
     }
-}
 
-fn main() {
-    gf_run();
+    #[test]
+    fn config_scopes_decide_what_is_touched() {
+        let tmp = flow_repo("config-scopes");
+        let repo = &tmp.repo;
+        let file = repo.path().join("team.gitconfig");
+        fs::write(&file, "").unwrap();
+
+        gf_config_set(Some(repo), &ConfigScope::File(file.clone()), "gitflow.branch.develop", "next", true).unwrap();
+        assert!(repo.find_branch("next", BranchType::Local).is_err());
+        assert_eq!(Config::open(&file).unwrap().get_string("gitflow.branch.develop").unwrap(), "next");
+        assert!(repo.config().unwrap().get_string("gitflow.branch.develop").is_err());
+
+        gf_config_set(Some(repo), &ConfigScope::Local, "gitflow.branch.develop", "next", true).unwrap();
+        let next = repo.find_branch("next", BranchType::Local).unwrap();
+        let develop = repo.find_branch("develop", BranchType::Local).unwrap();
+        assert_eq!(next.get().target(), develop.get().target());
+        assert_eq!(gf_develop(repo), "next");
+
+        assert!(gf_config_open(None, &ConfigScope::Default, false).is_err());
+        assert!(gf_config_open(None, &ConfigScope::File(file), true).is_ok());
+    }
+
+    #[test]
+    fn slugify_makes_names_git_accepts() {
+        assert_eq!(slugify("Fix the login page!"), "Fix-the-login-page");
+        assert_eq!(slugify("  a..b//c  "), "a.b/c");
+        assert_eq!(slugify("-x/.y"), "x/y");
+        assert_eq!(slugify("what? -- why?"), "what-why");
+        assert_eq!(slugify("name.lock.lock"), "name");
+        assert_eq!(slugify("Über ständig"), "Über-ständig");
+    }
+
+    #[test]
+    fn refname_problem_names_the_first_rule() {
+        assert_eq!(refname_problem("feature/a b"), "it contains spaces or control characters");
+        assert_eq!(refname_problem("feature/a~1"), "it contains one of ~ ^ : ? * [ \\");
+        assert_eq!(refname_problem("feature/a..b"), "it contains \"..\", \"@{\" or \"//\"");
+        assert_eq!(refname_problem("feature/.a"), "a part of it starts with \"-\" or \".\"");
+        assert_eq!(refname_problem("feature/a.lock"), "it ends with \"/\", \".\" or \".lock\"");
+    }
+
+    #[test]
+    fn check_name_explains_rejections() {
+        let tmp = flow_repo("check-name");
+        let repo = &tmp.repo;
+        let message = |name: &str| gf_check_name(repo, "feature", name).unwrap_err().message().to_owned();
+
+        assert!(gf_check_name(repo, "feature", "login").is_ok());
+        assert_eq!(message(""), "The feature name '' is rejected: it is empty");
+        assert_eq!(message("log in"),
+            "The feature name 'log in' is rejected: it contains spaces or control characters, try 'log-in' or --slugify");
+
+        let mut config = repo.config().unwrap();
+        config.set_i64("gitflow.name.maxlength", 5).unwrap();
+        assert_eq!(message("logout"), "The feature name 'logout' is rejected: it is longer than 5 characters");
+        config.set_str("gitflow.feature.name.pattern", "[A-Z]+-[0-9]+").unwrap();
+        assert!(gf_check_name(repo, "feature", "AB-12").is_ok());
+        assert!(gf_check_name(repo, "bugfix", "login").is_ok());
+        assert_eq!(message("login"),
+            "The feature name 'login' is rejected: it doesn't match [A-Z]+-[0-9]+ (gitflow.feature.name.pattern)");
+
+        create_checkout_branch(repo, "feature/AB-12", Some("develop"), None).unwrap();
+        assert_eq!(message("AB-12"), "The feature name 'AB-12' is rejected: branch feature/AB-12 already exists");
+    }
+
+    #[test]
+    fn merge_message_fills_in_templates() {
+        let tmp = flow_repo("merge-message");
+        let repo = &tmp.repo;
+        create_checkout_branch(repo, "release/1.2", Some("develop"), None).unwrap();
+        commit(repo, "b", "b", "Bump version\n\nTo 1.2.");
+        commit(repo, "c", "c", "Fix changelog");
+        let ctx = MergeContext { kind: "release", name: "1.2", branch: "release/1.2", tag: Some("v1.2") };
+
+        assert_eq!(merge_message(repo, &ctx, "develop", "release/1.2").unwrap(),
+            "Merge release/1.2 into develop\n\n* Bump version\n* Fix changelog");
+
+        let mut config = repo.config().unwrap();
+        config.set_str("gitflow.message.master", "{kind} {version} ({tag})\n\n\n\n{commits}").unwrap();
+        assert_eq!(merge_message(repo, &ctx, "master", "release/1.2").unwrap(),
+            "release 1.2 (v1.2)\n\n* Develop\n* Bump version\n* Fix changelog");
+        config.set_str("gitflow.release.message.master", "Release {name}\n\n{tag}\n\nfrom {branch}").unwrap();
+        let ctx = MergeContext { tag: None, ..ctx };
+        assert_eq!(merge_message(repo, &ctx, "master", "release/1.2").unwrap(), "Release 1.2\n\nfrom release/1.2");
+
+        let ctx = MergeContext { kind: "feature", name: "login", branch: "release/1.2", tag: None };
+        config.set_str("gitflow.message.base", "{name} {version}into {base}").unwrap();
+        let develop = repo.find_branch("develop", BranchType::Local).unwrap().get().peel_to_commit().unwrap();
+        repo.branch("stack", &develop, false).unwrap();
+        assert_eq!(merge_message(repo, &ctx, "stack", "release/1.2").unwrap(), "login into stack");
+    }
+
+    #[test]
+    fn squash_makes_a_single_commit() {
+        let tmp = flow_repo("squash");
+        let repo = &tmp.repo;
+        let ctx = MergeContext { kind: "feature", name: "login", branch: "feature/login", tag: None };
+        create_checkout_branch(repo, "feature/login", Some("develop"), None).unwrap();
+        assert_eq!(squash_merge_branch(repo, &ctx, "develop", "feature/login").unwrap_err().message(),
+            "feature/login has nothing to squash into develop");
+        commit(repo, "login", "form", "Add the form");
+        commit(repo, "login", "form and check", "Check the password");
+        let develop = repo.refname_to_id("refs/heads/develop").unwrap();
+
+        squash_merge_branch(repo, &ctx, "develop", "feature/login").unwrap();
+        let head = repo.head().unwrap();
+        assert_eq!(head.shorthand(), Some("develop"));
+        let squashed = head.peel_to_commit().unwrap();
+        assert_eq!(squashed.parent_ids().collect::<Vec<_>>(), [develop]);
+        assert_eq!(squashed.message(),
+            Some("Squash feature/login into develop\n\n* Add the form (Test)\n* Check the password (Test)\n"));
+        assert_eq!(fs::read_to_string(repo.workdir().unwrap().join("login")).unwrap(), "form and check");
+    }
+
+    #[test]
+    fn squash_refuses_conflicts() {
+        let tmp = flow_repo("squash-conflict");
+        let repo = &tmp.repo;
+        let ctx = MergeContext { kind: "feature", name: "login", branch: "feature/login", tag: None };
+        create_checkout_branch(repo, "feature/login", Some("develop"), None).unwrap();
+        commit(repo, "a", "feature", "Change a");
+        checkout_branch(repo, "develop").unwrap();
+        let develop = commit(repo, "a", "develop", "Change a too");
+
+        assert_eq!(squash_merge_branch(repo, &ctx, "develop", "feature/login").unwrap_err().message(),
+            "feature/login doesn't squash cleanly onto develop, rebase it first");
+        assert_eq!(repo.refname_to_id("refs/heads/develop").unwrap(), develop);
+    }
+
+    #[test]
+    fn edit_text_strips_comments_and_blank_lines() {
+        let tmp = temp_repo("edit-text");
+        let repo = &tmp.repo;
+        let editor = "edit() { cp \"$1\" \"$1.seen\"; printf 'Title  \\n\\n# note\\nBody\\n\\n\\n' > \"$1\"; }; edit";
+        repo.config().unwrap().set_str("core.editor", editor).unwrap();
+        assert_eq!(git_editor(repo), editor);
+
+        let text = edit_text(repo, "TAG_MSG", "Draft\n\n", "Enter the message.\n\nOr not.").unwrap();
+        assert_eq!(text, "Title\n\nBody");
+        let path = gf_state_path(repo, "TAG_MSG");
+        assert!(!path.exists());
+        let seen = fs::read_to_string(path.with_extension("seen")).unwrap();
+        assert_eq!(seen, "Draft\n\n# Enter the message.\n#\n# Or not.\n");
+    }
+
+    #[test]
+    fn edit_msg_aborts_on_an_empty_message() {
+        let tmp = temp_repo("edit-msg");
+        let repo = &tmp.repo;
+        assert_eq!(edit_msg(repo, "MERGE_MSG", "Merge", "").unwrap(), "Merge\n");
+
+        repo.config().unwrap().set_str("core.editor", ": >").unwrap();
+        assert_eq!(edit_msg(repo, "MERGE_MSG", "Merge", "").unwrap_err().message(),
+            "Aborting because of an empty message");
+        repo.config().unwrap().set_str("core.editor", "false").unwrap();
+        assert_eq!(edit_msg(repo, "MERGE_MSG", "Merge", "").unwrap_err().message(),
+            "The editor false failed, aborting");
+    }
+
+    fn cleanup_opts() -> CleanupOpts<'static> {
+        CleanupOpts { kinds: FLOW_KINDS.to_vec(), days: None, remote: false, yes: true, dry_run: true }
+    }
+
+    fn candidate_names(repo: &Repository) -> Vec<String> {
+        gf_cleanup_candidates(repo, &cleanup_opts()).unwrap().into_iter().map(|c| c.branch).collect()
+    }
+
+    #[test]
+    fn cleanup_takes_merged_branches() {
+        let temp = flow_repo("cleanup-merged");
+        let repo = &temp.repo;
+        gf_start(repo, "feature", "develop", "done", None).unwrap();
+        commit(repo, "c", "c", "Done");
+        fastforward_merge_branch(repo, "develop", "feature/done").unwrap();
+        checkout_branch(repo, "develop").unwrap();
+
+        assert_eq!(candidate_names(repo), vec!["feature/done"]);
+    }
+
+    #[test]
+    fn cleanup_leaves_branches_without_commits_of_their_own() {
+        let temp = flow_repo("cleanup-new");
+        let repo = &temp.repo;
+        gf_start(repo, "feature", "develop", "fresh", None).unwrap();
+        gf_start(repo, "feature", "master", "old", None).unwrap();
+        checkout_branch(repo, "develop").unwrap();
+
+        assert!(candidate_names(repo).is_empty());
+    }
+
+    #[test]
+    fn cleanup_leaves_unmerged_branches() {
+        let temp = flow_repo("cleanup-unmerged");
+        let repo = &temp.repo;
+        gf_start(repo, "feature", "develop", "wip", None).unwrap();
+        commit(repo, "c", "c", "Work in progress");
+        checkout_branch(repo, "develop").unwrap();
+
+        assert!(candidate_names(repo).is_empty());
+    }
 }
@@ -0,0 +1,228 @@
+// Output formats for the results other programs may want to read.
+//
+// With `--format json` or `--porcelain` the commands which have a result
+// (config, list, status, start, finish and publish) print one document on
+// stdout, and everything said along the way goes to stderr instead.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use git2::{Oid, Repository};
+use serde_json::{json, Value};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Human = 1,
+    Json,
+    Porcelain,
+}
+
+static FORMAT: AtomicUsize = AtomicUsize::new(Format::Human as usize);
+
+thread_local! {
+    static PUSHES: RefCell<Vec<Value>> = const { RefCell::new(Vec::new()) };
+}
+
+// println! for human readers, which moves to stderr when stdout carries a
+// document.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if $crate::output::is_human() {
+            println!($($arg)*);
+        } else {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "human" => Some(Format::Human),
+            "json" => Some(Format::Json),
+            "porcelain" => Some(Format::Porcelain),
+            _ => None,
+        }
+    }
+}
+
+pub fn set_format(format: Format) {
+    FORMAT.store(format as usize, Ordering::Relaxed);
+}
+
+pub fn format() -> Format {
+    match FORMAT.load(Ordering::Relaxed) {
+        2 => Format::Json,
+        3 => Format::Porcelain,
+        _ => Format::Human,
+    }
+}
+
+pub fn is_human() -> bool {
+    format() == Format::Human
+}
+
+// Print a document in the machine readable format, human output is done by
+// the commands themselves.
+pub fn emit(doc: &Value) {
+    match format() {
+        Format::Human => {}
+        Format::Json => println!("{}", serde_json::to_string_pretty(doc).unwrap_or_default()),
+        Format::Porcelain => {
+            let mut lines = Vec::new();
+            porcelain(doc, "", &mut lines);
+            for line in lines {
+                println!("{}", line);
+            }
+        }
+    }
+}
+
+// One "key value" line per scalar, keys are the dotted path into the
+// document and array elements are numbered from 0. Nulls are left out.
+fn porcelain(value: &Value, path: &str, lines: &mut Vec<String>) {
+    let join = |key: &str| if path.is_empty() { key.to_owned() } else { format!("{}.{}", path, key) };
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                porcelain(value, &join(key), lines);
+            }
+        }
+        Value::Array(values) => {
+            for (i, value) in values.iter().enumerate() {
+                porcelain(value, &join(&i.to_string()), lines);
+            }
+        }
+        Value::Null => {}
+        Value::String(s) => lines.push(format!("{} {}", path, s.replace('\n', "\\n"))),
+        other => lines.push(format!("{} {}", path, other)),
+    }
+}
+
+pub fn record_push(remote: &str, refspec: &str, error: Option<&str>) {
+    PUSHES.with(|pushes| pushes.borrow_mut().push(json!({
+        "remote": remote,
+        "refspec": refspec,
+        "ok": error.is_none(),
+        "error": error,
+    })));
+}
+
+fn take_pushes() -> Vec<Value> {
+    PUSHES.with(|pushes| pushes.borrow_mut().drain(..).collect())
+}
+
+// Branches and tags, to find out what an operation did by comparing the
+// snapshots from before and after it.
+pub fn ref_snapshot(repo: &Repository) -> BTreeMap<String, Oid> {
+    let mut refs = BTreeMap::new();
+    if let Ok(references) = repo.references() {
+        for reference in references.flatten() {
+            if let (Some(name), Some(oid)) = (reference.name(), reference.target()) {
+                if name.starts_with("refs/heads/") || name.starts_with("refs/tags/") {
+                    refs.insert(name.to_owned(), oid);
+                }
+            }
+        }
+    }
+    refs
+}
+
+pub fn operation_result(command: &str, kind: &str, name: &str, error: Option<&str>,
+                        before: &BTreeMap<String, Oid>, after: &BTreeMap<String, Oid>) -> Value {
+    let mut refs_moved = Vec::new();
+    let mut branches_created = Vec::new();
+    let mut branches_deleted = Vec::new();
+    let mut tags_created = Vec::new();
+
+    for (refname, oid) in after {
+        match before.get(refname) {
+            Some(old) if old != oid => refs_moved.push(json!({
+                "ref": refname,
+                "old": old.to_string(),
+                "new": oid.to_string(),
+            })),
+            Some(_) => {}
+            None => {
+                if let Some(tag) = refname.strip_prefix("refs/tags/") {
+                    tags_created.push(json!({ "name": tag, "oid": oid.to_string() }));
+                } else if let Some(branch) = refname.strip_prefix("refs/heads/") {
+                    branches_created.push(json!({ "name": branch, "oid": oid.to_string() }));
+                }
+            }
+        }
+    }
+    for (refname, oid) in before {
+        if let (Some(branch), None) = (refname.strip_prefix("refs/heads/"), after.get(refname)) {
+            branches_deleted.push(json!({ "name": branch, "oid": oid.to_string() }));
+        }
+    }
+
+    json!({
+        "command": command,
+        "kind": kind,
+        "name": name,
+        "ok": error.is_none(),
+        "error": error,
+        "refs_moved": refs_moved,
+        "branches_created": branches_created,
+        "branches_deleted": branches_deleted,
+        "tags_created": tags_created,
+        "pushes": take_pushes(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{commit, temp_repo};
+
+    #[test]
+    fn porcelain_flattens_documents() {
+        let doc = json!({
+            "name": "login",
+            "ok": true,
+            "error": null,
+            "message": "one\ntwo",
+            "pushes": [{ "remote": "origin" }, { "remote": "mirror" }],
+        });
+        let mut lines = Vec::new();
+        porcelain(&doc, "", &mut lines);
+        assert_eq!(lines, [
+            "message one\\ntwo",
+            "name login",
+            "ok true",
+            "pushes.0.remote origin",
+            "pushes.1.remote mirror",
+        ]);
+    }
+
+    #[test]
+    fn operation_result_compares_snapshots() {
+        let tmp = temp_repo("operation-result");
+        let repo = &tmp.repo;
+        let first = commit(repo, "a", "a", "First");
+        repo.branch("feature/old", &repo.find_commit(first).unwrap(), false).unwrap();
+        let before = ref_snapshot(repo);
+
+        let second = commit(repo, "b", "b", "Second");
+        repo.find_branch("feature/old", git2::BranchType::Local).unwrap().delete().unwrap();
+        repo.branch("feature/new", &repo.find_commit(second).unwrap(), false).unwrap();
+        repo.tag_lightweight("v1.0", &repo.find_object(second, None).unwrap(), false).unwrap();
+        record_push("origin", "refs/heads/master:refs/heads/master", Some("rejected"));
+        let after = ref_snapshot(repo);
+
+        let result = operation_result("finish", "feature", "new", None, &before, &after);
+        assert_eq!(result["ok"], json!(true));
+        assert_eq!(result["refs_moved"], json!([
+            { "ref": "refs/heads/master", "old": first.to_string(), "new": second.to_string() },
+        ]));
+        assert_eq!(result["branches_created"], json!([{ "name": "feature/new", "oid": second.to_string() }]));
+        assert_eq!(result["branches_deleted"], json!([{ "name": "feature/old", "oid": first.to_string() }]));
+        assert_eq!(result["tags_created"], json!([{ "name": "v1.0", "oid": second.to_string() }]));
+        assert_eq!(result["pushes"][0]["ok"], json!(false));
+        assert_eq!(result["pushes"][0]["error"], json!("rejected"));
+        assert_eq!(take_pushes().len(), 0);
+    }
+}
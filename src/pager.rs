@@ -0,0 +1,98 @@
+// Paging of long output such as diff and log.
+//
+// The pager is picked like git does, from GIT_PAGER, core.pager and PAGER
+// with less as the fallback, and only when stdout is a terminal. Writes
+// which fail because the reader went away (the pager was quit or a pipe
+// was closed) are dropped instead of failing the command.
+
+use std::env;
+use std::io::{self, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use git2::Repository;
+
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn disable() {
+    DISABLED.store(true, Ordering::Relaxed);
+}
+
+pub struct Pager {
+    child: Option<Child>,
+    closed: bool,
+}
+
+fn pager_command(repo: &Repository) -> Option<String> {
+    let command = env::var("GIT_PAGER").ok()
+        .or_else(|| repo.config().ok().and_then(|config| config.get_string("core.pager").ok()))
+        .or_else(|| env::var("PAGER").ok())
+        .unwrap_or_else(|| "less".to_owned());
+    if command.is_empty() || command == "cat" {
+        None
+    } else {
+        Some(command)
+    }
+}
+
+impl Pager {
+    pub fn start(repo: &Repository) -> Pager {
+        let human = crate::output::is_human();
+        let command = if human && !DISABLED.load(Ordering::Relaxed) && atty::is(atty::Stream::Stdout) {
+            pager_command(repo)
+        } else {
+            None
+        };
+
+        let child = command.and_then(|command| {
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c").arg(&command).stdin(Stdio::piped());
+            if env::var_os("LESS").is_none() {
+                cmd.env("LESS", "FRX");
+            }
+            if env::var_os("LV").is_none() {
+                cmd.env("LV", "-c");
+            }
+            cmd.spawn().ok()
+        });
+        Pager { child, closed: false }
+    }
+}
+
+impl Write for Pager {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed {
+            return Ok(buf.len());
+        }
+        let result = match self.child.as_mut().and_then(|child| child.stdin.as_mut()) {
+            Some(stdin) => stdin.write_all(buf),
+            None => io::stdout().write_all(buf),
+        };
+        match result {
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                self.closed = true;
+                Ok(buf.len())
+            }
+            Err(e) => Err(e),
+            Ok(()) => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.child.as_mut().and_then(|child| child.stdin.as_mut()) {
+            Some(stdin) => stdin.flush(),
+            None => io::stdout().flush(),
+        }.or_else(|e| if e.kind() == io::ErrorKind::BrokenPipe { Ok(()) } else { Err(e) })
+    }
+}
+
+impl Drop for Pager {
+    fn drop(&mut self) {
+        self.flush().unwrap_or(());
+        if let Some(mut child) = self.child.take() {
+            // closing stdin lets the pager see the end of the output
+            drop(child.stdin.take());
+            child.wait().map(|_| ()).unwrap_or(());
+        }
+    }
+}
@@ -0,0 +1,271 @@
+// Progress reporting for fetch and push.
+//
+// The mode is chosen once from `--progress` (or from whether stdout is a
+// terminal) and every transfer asks `reporter()` for a fresh reporter.
+// Progress goes to stderr so it never mixes with the command's own output.
+
+use std::cell::Cell;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde_json::json;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ProgressMode {
+    Quiet = 1,
+    Bar,
+    Plain,
+    Json,
+}
+
+// 0 means nobody picked a mode yet, so it is detected on first use.
+static MODE: AtomicUsize = AtomicUsize::new(0);
+
+impl ProgressMode {
+    pub fn from_name(name: &str) -> Option<ProgressMode> {
+        match name {
+            "auto" => Some(ProgressMode::detect()),
+            "quiet" => Some(ProgressMode::Quiet),
+            "bar" => Some(ProgressMode::Bar),
+            "plain" => Some(ProgressMode::Plain),
+            "json" => Some(ProgressMode::Json),
+            _ => None,
+        }
+    }
+
+    fn detect() -> ProgressMode {
+        if atty::is(atty::Stream::Stdout) {
+            ProgressMode::Bar
+        } else {
+            ProgressMode::Plain
+        }
+    }
+}
+
+pub fn set_mode(mode: ProgressMode) {
+    MODE.store(mode as usize, Ordering::Relaxed);
+}
+
+pub fn mode() -> ProgressMode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => ProgressMode::Quiet,
+        2 => ProgressMode::Bar,
+        3 => ProgressMode::Plain,
+        4 => ProgressMode::Json,
+        _ => {
+            let mode = ProgressMode::detect();
+            set_mode(mode);
+            mode
+        }
+    }
+}
+
+pub trait Reporter {
+    // `stage` is one of "counting", "compressing", "writing", "receiving"
+    // or "resolving".
+    fn update(&self, stage: &'static str, current: usize, total: usize, bytes: usize);
+    fn done(&self, summary: &str);
+}
+
+pub fn reporter(operation: &str, remote: &str) -> Box<dyn Reporter> {
+    match mode() {
+        ProgressMode::Quiet => Box::new(Quiet),
+        ProgressMode::Bar => Box::new(Bar::new(operation, remote)),
+        ProgressMode::Plain => Box::new(Plain::new(operation, remote)),
+        ProgressMode::Json => Box::new(Json::new(operation, remote)),
+    }
+}
+
+pub fn human_bytes(bytes: usize) -> String {
+    let units = ["bytes", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.2} {}", size, units[unit])
+    }
+}
+
+// Counting objects has no known total, so there is nothing to measure
+// against until the next stage starts.
+fn percent(current: usize, total: usize) -> Option<usize> {
+    (current * 100).checked_div(total)
+}
+
+fn counts(current: usize, total: usize, bytes: usize) -> String {
+    let mut counts = if total > 0 {
+        format!("({}/{})", current, total)
+    } else {
+        format!("({})", current)
+    };
+    if bytes > 0 {
+        counts += &format!(", {}", human_bytes(bytes));
+    }
+    counts
+}
+
+fn operation_label(operation: &str, remote: &str) -> String {
+    match operation {
+        "fetch" => format!("Fetching from {}", remote),
+        "push" => format!("Pushing to {}", remote),
+        _ => format!("{} {}", operation, remote),
+    }
+}
+
+struct Quiet;
+
+impl Reporter for Quiet {
+    fn update(&self, _stage: &'static str, _current: usize, _total: usize, _bytes: usize) {}
+    fn done(&self, _summary: &str) {}
+}
+
+// A single line which is redrawn in place, for terminals.
+struct Bar {
+    label: String,
+    last: Cell<(&'static str, usize)>,
+}
+
+impl Bar {
+    fn new(operation: &str, remote: &str) -> Bar {
+        Bar { label: operation_label(operation, remote), last: Cell::new(("", 0)) }
+    }
+}
+
+impl Reporter for Bar {
+    fn update(&self, stage: &'static str, current: usize, total: usize, bytes: usize) {
+        let line = match percent(current, total) {
+            Some(pct) => {
+                if self.last.get() == (stage, pct) {
+                    return;
+                }
+                self.last.set((stage, pct));
+
+                let width = 30;
+                let filled = pct.min(100) * width / 100;
+                format!("{}: {:<11} [{}{}] {:>3}% {}",
+                    self.label, stage, "#".repeat(filled), " ".repeat(width - filled),
+                    pct, counts(current, total, bytes))
+            }
+            None => format!("{}: {:<11} {}", self.label, stage, counts(current, total, bytes)),
+        };
+        eprint!("\r{}\u{1b}[K", line);
+        io::stderr().flush().unwrap_or(());
+    }
+
+    fn done(&self, summary: &str) {
+        eprintln!("\r{}: {}\u{1b}[K", self.label, summary);
+    }
+}
+
+// One line per stage and per ten percent, for logs.
+struct Plain {
+    label: String,
+    last: Cell<(&'static str, usize)>,
+}
+
+impl Plain {
+    fn new(operation: &str, remote: &str) -> Plain {
+        Plain { label: operation_label(operation, remote), last: Cell::new(("", 0)) }
+    }
+}
+
+impl Reporter for Plain {
+    fn update(&self, stage: &'static str, current: usize, total: usize, bytes: usize) {
+        let step = percent(current, total).map(|pct| pct / 10 * 10);
+        let (last_stage, last_step) = self.last.get();
+        if last_stage == stage && step.is_none_or(|step| last_step >= step) {
+            return;
+        }
+        self.last.set((stage, step.unwrap_or(0)));
+
+        match step {
+            Some(step) => eprintln!("{}: {} {}% {}", self.label, stage, step, counts(current, total, bytes)),
+            None => eprintln!("{}: {} {}", self.label, stage, counts(current, total, bytes)),
+        }
+    }
+
+    fn done(&self, summary: &str) {
+        eprintln!("{}: {}", self.label, summary);
+    }
+}
+
+// One JSON document per line, for other programs.
+struct Json {
+    operation: String,
+    remote: String,
+    last: Cell<(&'static str, usize)>,
+}
+
+impl Json {
+    fn new(operation: &str, remote: &str) -> Json {
+        let json = Json { operation: operation.to_owned(), remote: remote.to_owned(), last: Cell::new(("", 0)) };
+        json.emit(json!({ "event": "start" }));
+        json
+    }
+
+    fn emit(&self, mut event: serde_json::Value) {
+        event["operation"] = json!(self.operation);
+        event["remote"] = json!(self.remote);
+        eprintln!("{}", event);
+    }
+}
+
+impl Reporter for Json {
+    fn update(&self, stage: &'static str, current: usize, total: usize, bytes: usize) {
+        let pct = percent(current, total);
+        let (last_stage, last_pct) = self.last.get();
+        if last_stage == stage && pct.is_none_or(|pct| last_pct >= pct) {
+            return;
+        }
+        self.last.set((stage, pct.unwrap_or(0)));
+
+        self.emit(json!({
+            "event": "progress",
+            "stage": stage,
+            "current": current,
+            "total": total,
+            "bytes": bytes,
+        }));
+    }
+
+    fn done(&self, summary: &str) {
+        self.emit(json!({ "event": "done", "summary": summary }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn human_bytes_picks_a_unit() {
+        assert_eq!(human_bytes(0), "0 bytes");
+        assert_eq!(human_bytes(1023), "1023 bytes");
+        assert_eq!(human_bytes(1536), "1.50 KiB");
+        assert_eq!(human_bytes(3 * 1024 * 1024), "3.00 MiB");
+        assert_eq!(human_bytes(5 * 1024 * 1024 * 1024 * 1024), "5120.00 GiB");
+    }
+
+    #[test]
+    fn counts_without_a_total() {
+        assert_eq!(percent(5, 0), None);
+        assert_eq!(percent(5, 10), Some(50));
+        assert_eq!(counts(5, 0, 0), "(5)");
+        assert_eq!(counts(5, 10, 0), "(5/10)");
+        assert_eq!(counts(5, 10, 2048), "(5/10), 2.00 KiB");
+    }
+
+    #[test]
+    fn labels_and_modes() {
+        assert_eq!(operation_label("fetch", "origin"), "Fetching from origin");
+        assert_eq!(operation_label("push", "mirror"), "Pushing to mirror");
+        assert!(ProgressMode::from_name("quiet") == Some(ProgressMode::Quiet));
+        assert!(ProgressMode::from_name("json") == Some(ProgressMode::Json));
+        assert!(ProgressMode::from_name("loud").is_none());
+    }
+}
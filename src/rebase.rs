@@ -0,0 +1,704 @@
+// The rebase engine behind `<kind> rebase` and `finish --rebase`.
+//
+// Commits are replayed one by one onto a detached HEAD and the branch is
+// only moved once all of them applied, so an abort just has to go back to
+// the branch. Everything needed to pick up after a conflict lives in
+// `<gitdir>/gitflow/rebase`:
+//
+//   head-name      the branch being rebased, as a full ref name
+//   orig-head      where the branch was before
+//   onto           the commit the branch is rebased onto
+//   todo           the steps still to do, one "<action> <oid> <subject>" a line
+//   stopped        the step which stopped on a conflict
+//   rewritten      "<old> <new>" for every commit done so far
+//   rebase-merges  there when merges are recreated instead of left out
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use git2::{CherrypickOptions, Commit, Error, Oid, Repository, Sort, StatusOptions};
+use git2::build::CheckoutBuilder;
+
+pub enum Outcome {
+    Done,
+    // why it stopped, the conflicts are left in the work tree
+    Stopped(String),
+}
+
+pub struct Options {
+    // let the user edit the todo list first
+    pub interactive: bool,
+    // recreate merges, they are left out otherwise
+    pub merges: bool,
+    // move fixup!, squash! and amend! commits behind their targets
+    pub autosquash: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Action {
+    Pick,
+    Reword,
+    Squash,
+    Fixup,
+    // a fixup taking over the message, "fixup -C" in the todo
+    Amend,
+    Drop,
+    Merge,
+}
+
+impl Action {
+    fn from_name(name: &str) -> Option<Action> {
+        match name {
+            "p" | "pick" => Some(Action::Pick),
+            "r" | "reword" => Some(Action::Reword),
+            "s" | "squash" => Some(Action::Squash),
+            "f" | "fixup" => Some(Action::Fixup),
+            "d" | "drop" => Some(Action::Drop),
+            "m" | "merge" => Some(Action::Merge),
+            _ => None,
+        }
+    }
+
+    // whether it goes into the commit before
+    fn melds(self) -> bool {
+        self == Action::Squash || self == Action::Fixup || self == Action::Amend
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Action::Pick => "pick",
+            Action::Reword => "reword",
+            Action::Squash => "squash",
+            Action::Fixup => "fixup",
+            Action::Amend => "fixup -C",
+            Action::Drop => "drop",
+            Action::Merge => "merge",
+        }
+    }
+}
+
+struct Step {
+    action: Action,
+    oid: Oid,
+    subject: String,
+}
+
+impl Step {
+    // Abbreviated ids, as the todo list shows them, are looked up.
+    fn parse(repo: &Repository, line: &str) -> Result<Step, Error> {
+        let mut words = line.split_whitespace();
+        let bad = || Error::from_str(&format!("Can't read the rebase step \"{}\"", line));
+        let mut action = words.next().and_then(Action::from_name).ok_or_else(bad)?;
+        let mut id = words.next().ok_or_else(bad)?;
+        if action == Action::Fixup && (id == "-C" || id == "-c") {
+            action = Action::Amend;
+            id = words.next().ok_or_else(bad)?;
+        }
+        let oid = match Oid::from_str(id) {
+            Ok(oid) if id.len() == 40 => oid,
+            _ => repo.revparse_single(id).and_then(|obj| obj.peel_to_commit()).map_err(|_| bad())?.id(),
+        };
+        Ok(Step { action, oid, subject: words.collect::<Vec<_>>().join(" ") })
+    }
+
+    fn line(&self) -> String {
+        format!("{} {} {}", self.action.name(), self.oid, self.subject)
+    }
+}
+
+struct State {
+    head_name: String,
+    orig_head: Oid,
+    onto: Oid,
+    todo: Vec<Step>,
+    stopped: Option<Step>,
+    rewritten: HashMap<Oid, Oid>,
+    merges: bool,
+}
+
+fn state_dir(repo: &Repository) -> PathBuf {
+    repo.path().join("gitflow").join("rebase")
+}
+
+fn io_error(e: std::io::Error) -> Error {
+    Error::from_str(&format!("Can't keep the rebase state: {}", e))
+}
+
+impl State {
+    fn load(repo: &Repository) -> Result<State, Error> {
+        let dir = state_dir(repo);
+        let read = |name: &str| fs::read_to_string(dir.join(name)).map(|s| s.trim_end().to_owned());
+        let head_name = read("head-name").map_err(|_| Error::from_str("No rebase in progress"))?;
+        let todo = read("todo").unwrap_or_default();
+        let stopped = read("stopped").unwrap_or_default();
+        let mut rewritten = HashMap::new();
+        for line in read("rewritten").unwrap_or_default().lines() {
+            let mut ids = line.split(' ');
+            if let (Some(old), Some(new)) = (ids.next(), ids.next()) {
+                rewritten.insert(Oid::from_str(old)?, Oid::from_str(new)?);
+            }
+        }
+
+        Ok(State {
+            head_name,
+            orig_head: Oid::from_str(&read("orig-head").map_err(io_error)?)?,
+            onto: Oid::from_str(&read("onto").map_err(io_error)?)?,
+            todo: todo.lines().filter(|l| !l.is_empty()).map(|l| Step::parse(repo, l)).collect::<Result<_, _>>()?,
+            stopped: if stopped.is_empty() { None } else { Some(Step::parse(repo, &stopped)?) },
+            rewritten,
+            merges: dir.join("rebase-merges").exists(),
+        })
+    }
+
+    fn save(&self, repo: &Repository) -> Result<(), Error> {
+        let dir = state_dir(repo);
+        fs::create_dir_all(&dir).map_err(io_error)?;
+        let todo: Vec<String> = self.todo.iter().map(|step| step.line() + "\n").collect();
+        let stopped = self.stopped.as_ref().map(|step| step.line() + "\n").unwrap_or_default();
+        let rewritten: Vec<String> = self.rewritten.iter().map(|(old, new)| format!("{} {}\n", old, new)).collect();
+        for (name, content) in &[
+            ("head-name", format!("{}\n", self.head_name)),
+            ("orig-head", format!("{}\n", self.orig_head)),
+            ("onto", format!("{}\n", self.onto)),
+            ("todo", todo.concat()),
+            ("stopped", stopped),
+            ("rewritten", rewritten.concat()),
+        ] {
+            fs::write(dir.join(name), content).map_err(io_error)?;
+        }
+        if self.merges {
+            fs::write(dir.join("rebase-merges"), "").map_err(io_error)?;
+        }
+        Ok(())
+    }
+
+    // Where a parent of a replayed commit went. First parents from outside
+    // the rebased commits move to onto, other ones are merged as they are.
+    fn new_parent(&self, oid: Oid, first: bool) -> Oid {
+        match self.rewritten.get(&oid) {
+            Some(new) => *new,
+            None if first => self.onto,
+            None => oid,
+        }
+    }
+}
+
+pub fn in_progress(repo: &Repository) -> bool {
+    state_dir(repo).join("head-name").exists()
+}
+
+// The branch an unfinished rebase is rebasing.
+pub fn branch(repo: &Repository) -> Option<String> {
+    State::load(repo).ok().map(|state| state.head_name.trim_start_matches("refs/heads/").to_owned())
+}
+
+fn short(oid: Oid) -> String {
+    oid.to_string()[..7].to_owned()
+}
+
+fn is_dirty(repo: &Repository) -> Result<bool, Error> {
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(false).include_ignored(false);
+    Ok(!repo.statuses(Some(&mut opts))?.is_empty())
+}
+
+fn head_commit(repo: &Repository) -> Result<Commit<'_>, Error> {
+    repo.head()?.peel_to_commit()
+}
+
+const TODO_HELP: &str = "
+Commands:
+p, pick <commit> = use commit
+r, reword <commit> = use commit, but edit the commit message
+s, squash <commit> = use commit, but meld into the previous commit
+f, fixup [-C] <commit> = like \"squash\", but keep only the previous
+                         commit's message, or only this one's with -C
+d, drop <commit> = remove commit
+m, merge <commit> = recreate the merge commit (with --rebase-merges)
+
+The lines can be reordered, and removing a line drops its commit.
+Removing everything aborts the rebase.";
+
+// Let the user rework the todo list.
+fn edit_todo(repo: &Repository, todo: Vec<Step>, upstream: Oid, orig_head: Oid, onto: Oid) -> Result<Vec<Step>, Error> {
+    let text: String = todo.iter()
+        .map(|step| format!("{} {} {}\n", step.action.name(), short(step.oid), step.subject))
+        .collect();
+    let help = format!("Rebase {}..{} onto {} ({} commands)\n{}",
+                       short(upstream), short(orig_head), short(onto), todo.len(), TODO_HELP);
+
+    let edited = crate::edit_text(repo, "rebase/git-rebase-todo", &text, &help)?;
+    let todo = edited.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| Step::parse(repo, line))
+        .collect::<Result<Vec<_>, _>>()?;
+    if todo.is_empty() {
+        return Err(Error::from_str("Nothing to do"));
+    }
+    if let Some(step) = todo.iter().find(|step| step.action != Action::Drop) {
+        if step.action.melds() {
+            return Err(Error::from_str(&format!("Can't {} without a previous commit", step.action.name())));
+        }
+    }
+    Ok(todo)
+}
+
+// What a "fixup! <target>", "squash! <target>" or "amend! <target>" subject
+// asks for, with the prefixes of fixups of fixups taken off the target.
+fn autosquash_target(subject: &str) -> Option<(Action, &str)> {
+    let (action, mut target) = if let Some(target) = subject.strip_prefix("fixup! ") {
+        (Action::Fixup, target)
+    } else if let Some(target) = subject.strip_prefix("squash! ") {
+        (Action::Squash, target)
+    } else if let Some(target) = subject.strip_prefix("amend! ") {
+        (Action::Amend, target)
+    } else {
+        return None;
+    };
+    while let Some(rest) = ["fixup! ", "squash! ", "amend! "].iter().find_map(|p| target.strip_prefix(p)) {
+        target = rest;
+    }
+    Some((action, target.trim()))
+}
+
+// Move every fixup!, squash! and amend! commit right behind the commit it
+// names, by subject, by an abbreviated id or by the start of a subject.
+fn autosquash(todo: Vec<Step>) -> Vec<Step> {
+    let mut groups: Vec<Vec<Step>> = Vec::new();
+    for mut step in todo {
+        let found = match autosquash_target(&step.subject) {
+            Some((action, target)) if step.action == Action::Pick && !target.is_empty() => {
+                let heads: Vec<&Step> = groups.iter().map(|group| &group[0]).collect();
+                let found = heads.iter().position(|head| head.subject == target)
+                    .or_else(|| heads.iter().position(|head| {
+                        target.len() >= 4 && head.oid.to_string().starts_with(target)
+                    }))
+                    .or_else(|| heads.iter().position(|head| head.subject.starts_with(target)));
+                found.map(|n| (n, action))
+            }
+            _ => None,
+        };
+        match found {
+            Some((n, action)) => {
+                step.action = action;
+                groups[n].push(step);
+            }
+            None => groups.push(vec![step]),
+        }
+    }
+    groups.into_iter().flatten().collect()
+}
+
+// Rebase the commits of head_name which are not in upstream onto onto.
+pub fn start(repo: &Repository, head_name: &str, upstream: Oid, onto: Oid, opts: &Options) -> Result<Outcome, Error> {
+    if in_progress(repo) {
+        return Err(Error::from_str("A rebase is already in progress, continue, skip or abort it first"));
+    }
+    if is_dirty(repo)? {
+        return Err(Error::from_str("Commit or stash your changes before rebasing"));
+    }
+    let orig_head = repo.refname_to_id(head_name)?;
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    revwalk.push(orig_head)?;
+    revwalk.hide(upstream)?;
+    let mut todo = Vec::new();
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        let action = if commit.parent_count() < 2 {
+            Action::Pick
+        } else if opts.merges {
+            Action::Merge
+        } else {
+            // like git, merges are left out of a plain rebase
+            continue;
+        };
+        todo.push(Step { action, oid: commit.id(), subject: commit.summary().unwrap_or("").to_owned() });
+    }
+
+    if opts.autosquash {
+        todo = autosquash(todo);
+    }
+    if opts.interactive {
+        fs::create_dir_all(state_dir(repo)).map_err(io_error)?;
+        todo = edit_todo(repo, todo, upstream, orig_head, onto).inspect_err(|_| {
+            fs::remove_dir_all(state_dir(repo)).unwrap_or(());
+        })?;
+    }
+
+    let state = State {
+        head_name: head_name.to_owned(),
+        orig_head,
+        onto,
+        todo,
+        stopped: None,
+        rewritten: HashMap::new(),
+        merges: opts.merges,
+    };
+    state.save(repo)?;
+    repo.checkout_tree(repo.find_commit(onto)?.as_object(), None)?;
+    repo.set_head_detached(onto)?;
+
+    run(repo, state)
+}
+
+// After the conflicts of the stopped step were resolved and added.
+pub fn resume(repo: &Repository) -> Result<Outcome, Error> {
+    let mut state = State::load(repo)?;
+    if let Some(step) = state.stopped.take() {
+        if repo.index()?.has_conflicts() {
+            return Err(Error::from_str("There are still unresolved conflicts, fix them and git add the files"));
+        }
+        // the stopped step stays on disk when this fails
+        commit_step(repo, &state, &step)?;
+        state.rewritten.insert(step.oid, head_commit(repo)?.id());
+        state.save(repo)?;
+    }
+
+    run(repo, state)
+}
+
+// Leave out the stopped step and go on with the next one.
+pub fn skip(repo: &Repository) -> Result<Outcome, Error> {
+    let mut state = State::load(repo)?;
+    let step = state.stopped.take().ok_or_else(|| Error::from_str("There is no stopped step to skip"))?;
+    repo.checkout_head(Some(CheckoutBuilder::new().force()))?;
+    repo.cleanup_state()?;
+    state.rewritten.insert(step.oid, head_commit(repo)?.id());
+    state.save(repo)?;
+
+    run(repo, state)
+}
+
+pub fn abort(repo: &Repository) -> Result<(), Error> {
+    let state = State::load(repo)?;
+    repo.set_head(&state.head_name)?;
+    repo.checkout_head(Some(CheckoutBuilder::new().force()))?;
+    repo.cleanup_state()?;
+    fs::remove_dir_all(state_dir(repo)).map_err(io_error)
+}
+
+fn run(repo: &Repository, mut state: State) -> Result<Outcome, Error> {
+    while !state.todo.is_empty() {
+        let step = state.todo.remove(0);
+        match apply(repo, &state, &step) {
+            Ok(true) => {}
+            Ok(false) => {
+                let mut message = format!("Could not apply {} {}", short(step.oid), step.subject);
+                let index = repo.index()?;
+                for conflict in index.conflicts()? {
+                    let conflict = conflict?;
+                    if let Some(entry) = conflict.our.or(conflict.their).or(conflict.ancestor) {
+                        message += &format!("\n  conflict in {}", String::from_utf8_lossy(&entry.path));
+                    }
+                }
+                state.stopped = Some(step);
+                state.save(repo)?;
+                return Ok(Outcome::Stopped(message));
+            }
+            Err(e) => {
+                // whatever was applied stays, --continue commits it
+                state.stopped = Some(step);
+                state.save(repo)?;
+                return Err(e);
+            }
+        }
+        state.rewritten.insert(step.oid, head_commit(repo)?.id());
+        state.save(repo)?;
+    }
+
+    // everything applied, move the branch over
+    let new_head = head_commit(repo)?.id();
+    repo.find_reference(&state.head_name)?.set_target(new_head, "flow rebase: finished")?;
+    repo.set_head(&state.head_name)?;
+    fs::remove_dir_all(state_dir(repo)).map_err(io_error)?;
+
+    Ok(Outcome::Done)
+}
+
+// Move the detached HEAD, the work tree is clean between steps.
+fn move_head(repo: &Repository, oid: Oid) -> Result<(), Error> {
+    repo.checkout_tree(repo.find_commit(oid)?.as_object(), None)?;
+    repo.set_head_detached(oid)
+}
+
+// Returns false when the step conflicts.
+fn apply(repo: &Repository, state: &State, step: &Step) -> Result<bool, Error> {
+    let commit = repo.find_commit(step.oid)?;
+    let melds = step.action.melds();
+
+    // with merges the commits go where their first parent went, instead
+    // of one after the other
+    if state.merges && !melds && commit.parent_count() > 0 {
+        let base = state.new_parent(commit.parent_id(0)?, true);
+        if base != head_commit(repo)?.id() {
+            move_head(repo, base)?;
+        }
+    }
+    if step.action == Action::Drop {
+        return Ok(true);
+    }
+    let head = head_commit(repo)?;
+
+    if commit.parent_count() > 1 {
+        if melds {
+            return Err(Error::from_str(&format!("Can't {} the merge {}", step.action.name(), short(step.oid))));
+        }
+        if commit.parent_count() > 2 {
+            return Err(Error::from_str(&format!("Can't recreate the octopus merge {}", short(step.oid))));
+        }
+        let other = state.new_parent(commit.parent_id(1)?, false);
+        // nothing left to merge
+        if other == head.id() || repo.graph_descendant_of(head.id(), other)? {
+            return Ok(true);
+        }
+        if step.action != Action::Reword && commit.parent_id(0)? == head.id() && commit.parent_id(1)? == other {
+            move_head(repo, commit.id())?;
+            return Ok(true);
+        }
+        repo.merge(&[&repo.find_annotated_commit(other)?], None, None)?;
+    } else {
+        // a commit which already sits on HEAD is kept as it is
+        if step.action == Action::Pick && commit.parent_count() == 1 && commit.parent_id(0)? == head.id() {
+            move_head(repo, commit.id())?;
+            return Ok(true);
+        }
+        repo.cherrypick(&commit, Some(&mut CherrypickOptions::new()))?;
+    }
+    if repo.index()?.has_conflicts() {
+        return Ok(false);
+    }
+    commit_step(repo, state, step)?;
+
+    Ok(true)
+}
+
+// Commit the index for the step, keeping the original author. A pick
+// which changes nothing any more is dropped.
+fn commit_step(repo: &Repository, state: &State, step: &Step) -> Result<(), Error> {
+    let commit = repo.find_commit(step.oid)?;
+    let head = head_commit(repo)?;
+    let tree = repo.find_tree(repo.index()?.write_tree()?)?;
+    let committer = repo.signature()?;
+    let message = commit.message().unwrap_or("");
+
+    match step.action {
+        Action::Squash | Action::Fixup | Action::Amend => {
+            let message = match step.action {
+                Action::Fixup => head.message().unwrap_or("").to_owned(),
+                // the "amend! <subject>" paragraph only says where it goes
+                Action::Amend if message.starts_with("amend! ") => {
+                    message.split_once("\n").map(|(_, rest)| rest.trim_start()).unwrap_or("").to_owned()
+                }
+                Action::Amend => message.to_owned(),
+                _ => {
+                    let message = if message.starts_with("squash! ") {
+                        message.split_once("\n").map(|(_, rest)| rest.trim_start()).unwrap_or("")
+                    } else {
+                        message
+                    };
+                    let text = format!("{}\n\n{}", head.message().unwrap_or("").trim_end(), message);
+                    crate::edit_msg(repo, "rebase/SQUASH_MSG", &text, "This is a combination of 2 commits.")?
+                }
+            };
+            if message.trim().is_empty() {
+                return Err(Error::from_str("Aborting the squash because of an empty message"));
+            }
+            let parents: Vec<Commit> = head.parents().collect();
+            let parents: Vec<&Commit> = parents.iter().collect();
+            let new = repo.commit(None, &head.author(), &committer, &message, &tree, &parents)?;
+            repo.set_head_detached(new)?;
+        }
+        _ if commit.parent_count() > 1 || tree.id() != head.tree_id() => {
+            let message = if step.action == Action::Reword {
+                crate::edit_msg(repo, "rebase/COMMIT_EDITMSG", message,
+                                &format!("Please enter the new message for {}.", short(step.oid)))?
+            } else {
+                message.to_owned()
+            };
+            let mut parents = vec![head];
+            if commit.parent_count() > 1 {
+                parents.push(repo.find_commit(state.new_parent(commit.parent_id(1)?, false))?);
+            }
+            let parents: Vec<&Commit> = parents.iter().collect();
+            repo.commit(Some("HEAD"), &commit.author(), &committer, &message, &tree, &parents)?;
+        }
+        _ => {}
+    }
+    repo.cleanup_state()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::tests::{commit, temp_repo, TempRepo};
+
+    fn step(oid: &str, subject: &str) -> Step {
+        Step { action: Action::Pick, oid: Oid::from_str(oid).unwrap(), subject: subject.to_owned() }
+    }
+
+    fn lines(todo: &[Step]) -> Vec<String> {
+        todo.iter().map(|step| format!("{} {}", step.action.name(), step.subject)).collect()
+    }
+
+    #[test]
+    fn parse_reads_full_and_abbreviated_ids() {
+        let temp = temp_repo("rebase-parse");
+        let repo = &temp.repo;
+        let oid = commit(repo, "a", "a", "First");
+
+        let full = Step::parse(repo, &format!("pick {} First commit", oid)).unwrap();
+        assert!(full.action == Action::Pick);
+        assert_eq!(full.oid, oid);
+        assert_eq!(full.subject, "First commit");
+
+        let short = Step::parse(repo, &format!("r {}", &oid.to_string()[..7])).unwrap();
+        assert!(short.action == Action::Reword);
+        assert_eq!(short.oid, oid);
+        assert_eq!(short.subject, "");
+
+        let amend = Step::parse(repo, &format!("fixup -C {} First", oid)).unwrap();
+        assert!(amend.action == Action::Amend);
+        assert_eq!(amend.oid, oid);
+        assert_eq!(amend.line(), format!("fixup -C {} First", oid));
+    }
+
+    #[test]
+    fn parse_rejects_bad_steps() {
+        let temp = temp_repo("rebase-parse-bad");
+        let repo = &temp.repo;
+        let oid = commit(repo, "a", "a", "First");
+
+        assert!(Step::parse(repo, &format!("edit {} First", oid)).is_err());
+        assert!(Step::parse(repo, "pick").is_err());
+        assert!(Step::parse(repo, "fixup -C").is_err());
+        assert!(Step::parse(repo, "pick 0123abc First").is_err());
+    }
+
+    #[test]
+    fn autosquash_moves_fixups_behind_their_targets() {
+        let todo = vec![
+            step("1111111111111111111111111111111111111111", "Add a"),
+            step("2222222222222222222222222222222222222222", "Add b"),
+            step("3333333333333333333333333333333333333333", "fixup! Add a"),
+            step("4444444444444444444444444444444444444444", "squash! fixup! Add b"),
+            step("5555555555555555555555555555555555555555", "amend! 11111111"),
+            step("6666666666666666666666666666666666666666", "fixup! Add"),
+            step("7777777777777777777777777777777777777777", "fixup! Nothing like it"),
+        ];
+
+        assert_eq!(lines(&autosquash(todo)), vec![
+            "pick Add a",
+            "fixup fixup! Add a",
+            "fixup -C amend! 11111111",
+            "fixup fixup! Add",
+            "pick Add b",
+            "squash squash! fixup! Add b",
+            "pick fixup! Nothing like it",
+        ]);
+    }
+
+    #[test]
+    fn autosquash_leaves_other_actions_alone() {
+        let mut todo = vec![
+            step("1111111111111111111111111111111111111111", "Add a"),
+            step("2222222222222222222222222222222222222222", "Add b"),
+            step("3333333333333333333333333333333333333333", "fixup! Add a"),
+        ];
+        todo[2].action = Action::Merge;
+
+        assert_eq!(lines(&autosquash(todo)), vec!["pick Add a", "pick Add b", "merge fixup! Add a"]);
+    }
+
+    const OPTIONS: Options = Options { interactive: false, merges: false, autosquash: false };
+
+    // topic changes file a where master changed it too, then adds b.
+    fn conflicting_repo(name: &str) -> (TempRepo, Oid, Oid) {
+        let temp = temp_repo(name);
+        let repo = &temp.repo;
+        let base = commit(repo, "a", "base\n", "Base");
+        repo.branch("topic", &repo.find_commit(base).unwrap(), false).unwrap();
+        let master = commit(repo, "a", "master\n", "Change a on master");
+
+        repo.set_head("refs/heads/topic").unwrap();
+        repo.checkout_head(Some(CheckoutBuilder::new().force())).unwrap();
+        commit(repo, "a", "topic\n", "Change a on topic");
+        let topic = commit(repo, "b", "b\n", "Add b");
+        (temp, master, topic)
+    }
+
+    fn subjects(repo: &Repository, refname: &str, count: usize) -> Vec<String> {
+        let mut revwalk = repo.revwalk().unwrap();
+        revwalk.push_ref(refname).unwrap();
+        revwalk.take(count)
+            .map(|oid| repo.find_commit(oid.unwrap()).unwrap().summary().unwrap().to_owned())
+            .collect()
+    }
+
+    fn stop(repo: &Repository, master: Oid) {
+        match start(repo, "refs/heads/topic", master, master, &OPTIONS).unwrap() {
+            Outcome::Stopped(message) => assert!(message.contains("conflict in a"), "{}", message),
+            Outcome::Done => panic!("the rebase should have stopped"),
+        }
+        assert!(in_progress(repo));
+        assert_eq!(branch(repo).as_deref(), Some("topic"));
+    }
+
+    fn commit_resolution(repo: &Repository) {
+        fs::write(repo.workdir().unwrap().join("a"), "resolved\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("a")).unwrap();
+        index.write().unwrap();
+    }
+
+    #[test]
+    fn continue_commits_the_resolution() {
+        let (temp, master, _) = conflicting_repo("rebase-continue");
+        let repo = &temp.repo;
+        stop(repo, master);
+
+        assert!(resume(repo).is_err(), "conflicts are still there");
+        commit_resolution(repo);
+        assert!(matches!(resume(repo).unwrap(), Outcome::Done));
+
+        assert!(!in_progress(repo));
+        assert_eq!(repo.head().unwrap().name(), Some("refs/heads/topic"));
+        assert_eq!(subjects(repo, "refs/heads/topic", 3),
+                   vec!["Add b", "Change a on topic", "Change a on master"]);
+        assert_eq!(fs::read_to_string(repo.workdir().unwrap().join("a")).unwrap(), "resolved\n");
+    }
+
+    #[test]
+    fn skip_leaves_the_stopped_commit_out() {
+        let (temp, master, _) = conflicting_repo("rebase-skip");
+        let repo = &temp.repo;
+        stop(repo, master);
+
+        assert!(matches!(skip(repo).unwrap(), Outcome::Done));
+
+        assert!(!in_progress(repo));
+        assert_eq!(subjects(repo, "refs/heads/topic", 2), vec!["Add b", "Change a on master"]);
+        assert_eq!(fs::read_to_string(repo.workdir().unwrap().join("a")).unwrap(), "master\n");
+    }
+
+    #[test]
+    fn abort_goes_back_to_the_branch() {
+        let (temp, master, topic) = conflicting_repo("rebase-abort");
+        let repo = &temp.repo;
+        stop(repo, master);
+
+        abort(repo).unwrap();
+
+        assert!(!in_progress(repo));
+        assert!(!state_dir(repo).exists());
+        assert_eq!(repo.head().unwrap().name(), Some("refs/heads/topic"));
+        assert_eq!(repo.refname_to_id("refs/heads/topic").unwrap(), topic);
+        assert_eq!(fs::read_to_string(repo.workdir().unwrap().join("a")).unwrap(), "topic\n");
+        assert!(!repo.index().unwrap().has_conflicts());
+    }
+}
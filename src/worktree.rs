@@ -0,0 +1,180 @@
+// Flow branches in linked worktrees.
+//
+// `start --worktree` and `checkout --worktree` give a flow branch its own
+// work tree, by default in `gitflow.path.worktrees`/<branch>. The directory
+// is taken relative to the main work tree and defaults to
+// `../<name>-worktrees` next to it. A branch can only be checked out in one
+// work tree at a time, which is what `elsewhere` is asked about before a
+// branch is moved or deleted.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use git2::{Error, Repository, StatusOptions, WorktreeAddOptions, WorktreePruneOptions};
+
+// The repository of the main work tree, also when running in a linked one.
+fn main_repo(repo: &Repository) -> Result<Repository, Error> {
+    if repo.is_worktree() {
+        // a linked worktree's git dir is <common dir>/worktrees/<name>
+        let common = repo.path().parent().and_then(Path::parent)
+            .ok_or_else(|| Error::from_str("Can't find the main repository of this worktree"))?;
+        Repository::open(common)
+    } else {
+        Repository::open(repo.path())
+    }
+}
+
+fn same_dir(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+pub struct Checkout {
+    pub path: PathBuf,
+    // the full ref name of the checked out branch
+    pub branch: Option<String>,
+    // the name of a linked worktree, None for the main one
+    pub name: Option<String>,
+}
+
+// Every work tree of the repository with the branch it has checked out.
+pub fn list(repo: &Repository) -> Result<Vec<Checkout>, Error> {
+    let main = main_repo(repo)?;
+    let branch_of = |repo: &Repository| {
+        repo.head().ok().filter(|head| head.is_branch()).and_then(|head| head.name().map(str::to_owned))
+    };
+
+    let mut checkouts = Vec::new();
+    if let Some(workdir) = main.workdir() {
+        checkouts.push(Checkout { path: workdir.to_owned(), branch: branch_of(&main), name: None });
+    }
+    for name in main.worktrees()?.iter().flatten() {
+        let worktree = main.find_worktree(name)?;
+        // one whose directory went away only waits for a prune
+        if worktree.validate().is_err() {
+            continue;
+        }
+        let linked = Repository::open_from_worktree(&worktree)?;
+        checkouts.push(Checkout {
+            path: worktree.path().to_owned(),
+            branch: branch_of(&linked),
+            name: Some(name.to_owned()),
+        });
+    }
+    Ok(checkouts)
+}
+
+// The work tree other than this one which has br_name checked out.
+pub fn elsewhere(repo: &Repository, br_name: &str) -> Result<Option<PathBuf>, Error> {
+    let refname = "refs/heads/".to_owned() + br_name;
+    let here = repo.workdir();
+    Ok(list(repo)?.into_iter()
+        .find(|checkout| {
+            checkout.branch.as_deref() == Some(&refname)
+                && !here.is_some_and(|here| same_dir(here, &checkout.path))
+        })
+        .map(|checkout| checkout.path))
+}
+
+// Where a new worktree for br_name goes when no path is given.
+pub fn default_path(repo: &Repository, br_name: &str) -> Result<PathBuf, Error> {
+    let main = main_repo(repo)?;
+    let top = main.workdir().ok_or_else(|| Error::from_str("A bare repository has no main worktree"))?;
+    let dir = match main.config()?.get_path("gitflow.path.worktrees") {
+        Ok(dir) => top.join(dir),
+        Err(_) => {
+            let name = top.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+            top.join("..").join(format!("{}-worktrees", name))
+        }
+    };
+    Ok(dir.join(br_name))
+}
+
+// Check br_name out in a new worktree at path.
+pub fn add(repo: &Repository, br_name: &str, path: &Path) -> Result<(), Error> {
+    if let Some(other) = elsewhere(repo, br_name)? {
+        return Err(Error::from_str(&format!("{} is already checked out in {}", br_name, other.display())));
+    }
+    if path.exists() {
+        return Err(Error::from_str(&format!("{} already exists", path.display())));
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| Error::from_str(&format!("Can't create {}: {}", parent.display(), e)))?;
+    }
+    let main = main_repo(repo)?;
+    let reference = main.find_reference(&("refs/heads/".to_owned() + br_name))?;
+    // worktree names can't have slashes, so feature/x becomes feature-x
+    let name = br_name.replace('/', "-");
+    main.worktree(&name, path, Some(WorktreeAddOptions::new().reference(Some(&reference))))?;
+    Ok(())
+}
+
+// Remove the worktree at path, unless it has changes which would be lost.
+pub fn remove(repo: &Repository, path: &Path) -> Result<(), Error> {
+    let main = main_repo(repo)?;
+    let name = list(repo)?.into_iter()
+        .find(|checkout| same_dir(&checkout.path, path))
+        .and_then(|checkout| checkout.name)
+        .ok_or_else(|| Error::from_str(&format!("{} is not a linked worktree", path.display())))?;
+    let worktree = main.find_worktree(&name)?;
+
+    let linked = Repository::open_from_worktree(&worktree)?;
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(true).include_ignored(false);
+    if !linked.statuses(Some(&mut opts))?.is_empty() {
+        return Err(Error::from_str(&format!("{} has changes, commit or remove them first", path.display())));
+    }
+
+    worktree.prune(Some(WorktreePruneOptions::new().valid(true).working_tree(true)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{commit, temp_repo};
+
+    #[test]
+    fn default_path_is_next_to_the_main_worktree() {
+        let tmp = temp_repo("worktree-path");
+        let repo = &tmp.repo;
+        let top = repo.workdir().unwrap().to_owned();
+        let name = top.file_name().unwrap().to_string_lossy().into_owned();
+        assert_eq!(default_path(repo, "feature/login").unwrap(),
+            top.join("..").join(format!("{}-worktrees", name)).join("feature/login"));
+
+        repo.config().unwrap().set_str("gitflow.path.worktrees", "trees").unwrap();
+        assert_eq!(default_path(repo, "feature/login").unwrap(), top.join("trees/feature/login"));
+    }
+
+    #[test]
+    fn branches_are_checked_out_in_one_worktree() {
+        let tmp = temp_repo("worktree-add");
+        let repo = &tmp.repo;
+        let first = commit(repo, "a", "a", "First");
+        repo.branch("feature/login", &repo.find_commit(first).unwrap(), false).unwrap();
+        // inside the git dir, so that it goes away with the repository
+        let path = repo.path().join("trees/feature-login");
+
+        assert!(elsewhere(repo, "feature/login").unwrap().is_none());
+        add(repo, "feature/login", &path).unwrap();
+        assert!(same_dir(&elsewhere(repo, "feature/login").unwrap().unwrap(), &path));
+        assert_eq!(add(repo, "feature/login", &repo.path().join("trees/other")).unwrap_err().message(),
+            format!("feature/login is already checked out in {}", path.display()));
+        assert_eq!(list(repo).unwrap().len(), 2);
+
+        let linked = Repository::open(&path).unwrap();
+        assert!(elsewhere(&linked, "feature/login").unwrap().is_none());
+        assert!(same_dir(&elsewhere(&linked, "master").unwrap().unwrap(), repo.workdir().unwrap()));
+
+        fs::write(path.join("new"), "new").unwrap();
+        assert!(remove(repo, &path).is_err());
+        fs::remove_file(path.join("new")).unwrap();
+        remove(repo, &path).unwrap();
+        assert!(!path.exists());
+        assert_eq!(list(repo).unwrap().len(), 1);
+        assert!(remove(repo, repo.workdir().unwrap()).is_err());
+    }
+}